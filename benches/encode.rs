@@ -0,0 +1,47 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tauri_inliner::{encode_base64, inline_html_string, Config};
+
+static SIZES: &[usize] = &[16 * 1024, 256 * 1024, 4 * 1024 * 1024];
+
+fn asset(size: usize) -> Vec<u8> {
+  (0..size).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+/// The base64 stage on its own; compare runs with and without the `simd` feature.
+fn encode(c: &mut Criterion) {
+  let mut group = c.benchmark_group("encode");
+  for size in SIZES {
+    let raw = asset(*size);
+    group.throughput(Throughput::Bytes(*size as u64));
+    group.bench_with_input(BenchmarkId::from_parameter(size), &raw, |b, raw| {
+      b.iter(|| encode_base64(raw))
+    });
+  }
+  group.finish();
+}
+
+/// A document with a single image of each size, end to end.
+fn inline_image(c: &mut Criterion) {
+  let dir = std::env::temp_dir().join("tauri-inliner-bench");
+  std::fs::create_dir_all(&dir).unwrap();
+
+  let mut group = c.benchmark_group("inline_image");
+  for size in SIZES {
+    let name = format!("image-{}.png", size);
+    std::fs::write(dir.join(&name), asset(*size)).unwrap();
+    let html = format!("<img src=\"{}\">", name);
+    let config = Config {
+      max_inline_size: *size,
+      ..Default::default()
+    };
+
+    group.throughput(Throughput::Bytes(*size as u64));
+    group.bench_with_input(BenchmarkId::from_parameter(size), &html, |b, html| {
+      b.iter(|| inline_html_string(html, &dir, config.clone()).unwrap())
+    });
+  }
+  group.finish();
+}
+
+criterion_group!(benches, encode, inline_image);
+criterion_main!(benches);
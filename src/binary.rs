@@ -1,31 +1,486 @@
 use kuchiki::NodeRef;
 
-use std::{collections::HashMap, path::PathBuf};
-
-pub fn inline_base64(
-  mut cache: &mut HashMap<String, Option<String>>,
-  config: &super::Config,
-  root_path: &PathBuf,
-  document: &NodeRef,
-) -> crate::Result<()> {
-  for target in document
-    .select(r#"video, img, link[rel=icon], link[rel="shortcut icon"], link[rel="apple-touch-icon"], link[rel="apple-touch-startup-image"]"#)
+use crate::{Asset, Context, DeviceProfile, IconPolicy, MediaSources};
+
+/// The `rel` of the icon links, matched case-insensitively.
+static ICON_RELS: &[&str] = &[
+  "icon",
+  "shortcut icon",
+  "apple-touch-icon",
+  "apple-touch-startup-image",
+  "mask-icon",
+];
+
+/// The `name` of the Windows tile image `<meta>` tags, matched case-insensitively.
+static TILE_IMAGE_META: &[&str] = &[
+  "msapplication-tileimage",
+  "msapplication-square70x70logo",
+  "msapplication-square150x150logo",
+  "msapplication-wide310x150logo",
+  "msapplication-square310x310logo",
+];
+
+/// The `property` or `name` of the social preview image `<meta>` tags, matched case-insensitively.
+static SOCIAL_IMAGE_META: &[&str] = &[
+  "og:image",
+  "og:image:url",
+  "og:image:secure_url",
+  "twitter:image",
+  "twitter:image:src",
+];
+
+/// Whether `element` is a social preview image `<meta>` tag.
+fn is_social_image(element: &kuchiki::ElementData) -> bool {
+  let attributes = element.attributes.borrow();
+  attributes
+    .get("property")
+    .or_else(|| attributes.get("name"))
+    .is_some_and(|name| SOCIAL_IMAGE_META.contains(&name.to_lowercase().as_str()))
+}
+
+/// The key of `element` in `Config::icon_links` if it's an icon link or a tile image `<meta>` tag.
+fn icon_key(element: &kuchiki::ElementData) -> Option<String> {
+  let (attribute, keys) = match &*element.name.local {
+    "link" => ("rel", ICON_RELS),
+    "meta" => ("name", TILE_IMAGE_META),
+    _ => return None,
+  };
+  let key = element.attributes.borrow().get(attribute)?.to_lowercase();
+  if keys.contains(&key.as_str()) {
+    Some(key)
+  } else {
+    None
+  }
+}
+
+/// Whether the `media` of an `apple-touch-startup-image` link applies to `device`, in either
+/// orientation. Only the `device-width`, `device-height` and `-webkit-device-pixel-ratio`
+/// features (and their `min-`/`max-` forms) are evaluated, the other ones match.
+fn media_matches_device(media: &str, device: &DeviceProfile) -> bool {
+  media.split(',').any(|query| {
+    query
+      .split('(')
+      .skip(1)
+      .filter_map(|feature| feature.split(')').next()?.split_once(':'))
+      .all(|(name, value)| {
+        let name = name.trim().to_lowercase();
+        let (comparison, name) = match name.strip_prefix("min-") {
+          Some(name) => (std::cmp::Ordering::Greater, name),
+          None => match name.strip_prefix("max-") {
+            Some(name) => (std::cmp::Ordering::Less, name),
+            None => (std::cmp::Ordering::Equal, name.as_str()),
+          },
+        };
+        let actual = match name {
+          "device-width" => device.width as f32,
+          "device-height" => device.height as f32,
+          "-webkit-device-pixel-ratio" => device.pixel_ratio,
+          _ => return true,
+        };
+        let expected = match value.trim().trim_end_matches("px").parse::<f32>() {
+          Ok(expected) => expected,
+          Err(_) => return true,
+        };
+        let ordering = actual
+          .partial_cmp(&expected)
+          .unwrap_or(std::cmp::Ordering::Equal);
+        ordering == std::cmp::Ordering::Equal || ordering == comparison
+      })
+  })
+}
+
+/// Splits a `srcset` attribute into its `(url, descriptors)` candidates.
+pub(crate) fn parse_srcset(srcset: &str) -> Vec<(String, String)> {
+  let mut candidates = vec![];
+  let mut rest = srcset;
+  loop {
+    rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+    if rest.is_empty() {
+      return candidates;
+    }
+    let url_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    let url = &rest[..url_end];
+    rest = &rest[url_end..];
+    // a URL ending with a comma has no descriptors
+    if url.ends_with(',') {
+      candidates.push((url.trim_end_matches(',').to_string(), String::new()));
+      continue;
+    }
+    let mut depth = 0;
+    let descriptors_end = rest
+      .char_indices()
+      .find(|(_, c)| {
+        match c {
+          '(' => depth += 1,
+          ')' => depth -= 1,
+          ',' => return depth == 0,
+          _ => {}
+        }
+        false
+      })
+      .map(|(i, _)| i)
+      .unwrap_or(rest.len());
+    candidates.push((url.to_string(), rest[..descriptors_end].trim().to_string()));
+    rest = &rest[descriptors_end..];
+  }
+}
+
+/// Inlines every candidate of a `srcset` attribute, keeping the ones that can't be inlined.
+pub(crate) fn inline_srcset(ctx: &mut Context, srcset: &str) -> crate::Result<String> {
+  let mut candidates = vec![];
+  for (url, descriptors) in parse_srcset(srcset) {
+    let url = crate::get(ctx, &url)?.unwrap_or(url);
+    if descriptors.is_empty() {
+      candidates.push(url);
+    } else {
+      candidates.push(format!("{} {}", url, descriptors));
+    }
+  }
+  Ok(candidates.join(", "))
+}
+
+/// Uses `content_type` as the MIME type of the data URI of a resolved asset, see `crate::get_as`.
+fn with_content_type(resolved: String, content_type: &str) -> Option<String> {
+  let data = resolved.strip_prefix("data:")?;
+  let parameters = data.find([';', ','])?;
+  Some(format!("data:{}{}", content_type, &data[parameters..]))
+}
+
+/// Inlines a single `<source>` of the media elements, see `MediaSources`.
+pub fn select_media_sources(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  let policy = ctx.config.media_sources;
+  if policy == MediaSources::All {
+    return Ok(());
+  }
+  // the sources are ignored when the element has a `src`
+  let media: Vec<NodeRef> = document
+    .select("video:not([src]), audio:not([src])")
     .unwrap()
-  {
-    let node = target.as_node();
+    .map(|media| media.as_node().clone())
+    .collect();
+
+  for media in media {
+    let sources: Vec<NodeRef> = media
+      .children()
+      .filter(|child| {
+        child.as_element().is_some_and(|child| {
+          &*child.name.local == "source" && child.attributes.borrow().contains("src")
+        })
+      })
+      .collect();
+
+    let mut chosen = None;
+    for (i, source) in sources.iter().enumerate() {
+      let src = source
+        .as_element()
+        .unwrap()
+        .attributes
+        .borrow()
+        .get("src")
+        .unwrap()
+        .to_string();
+      if let Some(resolved) = crate::get(ctx, &src)? {
+        chosen = Some((i, src, resolved));
+        break;
+      }
+    }
+    let (index, src, resolved) = match chosen {
+      Some(chosen) => chosen,
+      None => continue,
+    };
+
+    log::debug!(
+      "[INLINER] keeping the `{}` source of {}",
+      src,
+      media.to_string()
+    );
+    for (i, source) in sources.iter().enumerate() {
+      if i != index || policy == MediaSources::Collapse {
+        source.detach();
+      }
+    }
+    let target = if policy == MediaSources::Collapse {
+      &media
+    } else {
+      &sources[index]
+    };
+    let mut attributes = target.as_element().unwrap().attributes.borrow_mut();
+    if ctx.config.annotate_sources {
+      attributes.insert(crate::ANNOTATION_ATTRIBUTE, src);
+    }
+    attributes.insert("src", resolved);
+  }
+
+  Ok(())
+}
+
+pub fn inline_base64(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  let targets: Vec<NodeRef> = document
+    .select("video, audio, img, input[type=image], object[data], embed[src], picture > source, video > source, audio > source, video > track, audio > track, link[rel], meta[content], body[background], table[background], thead[background], tbody[background], tfoot[background], tr[background], td[background], th[background]")
+    .unwrap()
+    .filter(|target| match &*target.name.local {
+      "link" => icon_key(target).is_some(),
+      "meta" => {
+        icon_key(target).is_some() || (ctx.config.inline_social_images && is_social_image(target))
+      }
+      _ => true,
+    })
+    .map(|target| target.as_node().clone())
+    .collect();
+
+  for node in targets {
     let element = node.as_element().unwrap();
+    if let Some(key) = icon_key(element) {
+      match ctx
+        .config
+        .icon_links
+        .get(&key)
+        .copied()
+        .unwrap_or(IconPolicy::Inline)
+      {
+        IconPolicy::Inline => {}
+        IconPolicy::Keep => continue,
+        IconPolicy::Remove => {
+          log::debug!("[INLINER] removing {}", node.to_string());
+          node.detach();
+          continue;
+        }
+      }
+    }
+    if let (Some(devices), Some(media)) = (
+      &ctx.config.startup_image_devices,
+      element.attributes.borrow().get("media"),
+    ) {
+      let is_startup_image = icon_key(element).as_deref() == Some("apple-touch-startup-image");
+      if is_startup_image
+        && !devices
+          .iter()
+          .any(|device| media_matches_device(media, device))
+      {
+        log::debug!("[INLINER] removing {} matching no device", node.to_string());
+        node.detach();
+        continue;
+      }
+    }
     let attr = match element.name.local.to_string().as_str() {
-      "video" | "img" => "src",
+      "video" | "audio" | "img" | "input" | "source" | "track" | "embed" => "src",
+      "object" => "data",
+      "meta" => "content",
       "link" => "href",
+      // the legacy attribute is still common in email templates
+      "body" | "table" | "thead" | "tbody" | "tfoot" | "tr" | "td" | "th" => "background",
       _ => panic!("tag not implemented"),
     };
     let mut attributes = element.attributes.borrow_mut();
-    if let Some(source) = attributes.get(attr) {
+    if let Some(source) = attributes.get(attr).map(|source| source.to_string()) {
       log::debug!("[INLINER] inlining {} on {}", attr, node.to_string());
-      if let Some(resolve_source) = crate::get(&mut cache, source, &config, &root_path)? {
+      // `<object>` and `<embed>` declare the type of their resource
+      let resolve_source = match attributes.get("type").map(str::trim) {
+        Some(content_type)
+          if !content_type.is_empty() && matches!(&*element.name.local, "object" | "embed") =>
+        {
+          crate::get_as(ctx, &source, content_type)?
+            .and_then(|resolved| with_content_type(resolved, content_type))
+        }
+        _ => crate::get(ctx, &source)?,
+      };
+      if let Some(resolve_source) = resolve_source {
+        if ctx.config.annotate_sources {
+          attributes.insert(crate::ANNOTATION_ATTRIBUTE, source);
+        }
         attributes.insert(attr, resolve_source);
       }
     }
+    if let Some(srcset) = attributes.get("srcset").map(|srcset| srcset.to_string()) {
+      log::debug!("[INLINER] inlining srcset on {}", node.to_string());
+      attributes.insert("srcset", inline_srcset(ctx, &srcset)?);
+    }
+    // the poster is usually small enough to be inlined even when the video isn't
+    if &*element.name.local == "video" {
+      if let Some(poster) = attributes.get("poster").map(|poster| poster.to_string()) {
+        log::debug!("[INLINER] inlining poster on {}", node.to_string());
+        if let Some(resolve_poster) = crate::get(ctx, &poster)? {
+          attributes.insert("poster", resolve_poster);
+        }
+      }
+    }
+  }
+
+  Ok(())
+}
+
+/// Inlines the URLs of `Config::lazy_attributes`, promoting them when
+/// `Config::promote_lazy_attributes` is set.
+pub fn inline_lazy_attributes(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  if ctx.config.lazy_attributes.is_empty() {
+    return Ok(());
+  }
+  let lazy_attributes = ctx.config.lazy_attributes.clone();
+  let targets: Vec<NodeRef> = document
+    .select("*")
+    .unwrap()
+    .filter(|target| {
+      let attributes = target.attributes.borrow();
+      lazy_attributes
+        .iter()
+        .any(|name| attributes.contains(name.as_str()))
+    })
+    .map(|target| target.as_node().clone())
+    .collect();
+
+  for node in targets {
+    let element = node.as_element().unwrap();
+    let mut attributes = element.attributes.borrow_mut();
+    for name in &lazy_attributes {
+      let value = match attributes.get(name.as_str()) {
+        Some(value) => value.to_string(),
+        None => continue,
+      };
+      log::debug!("[INLINER] inlining {} on {}", name, node.to_string());
+      let resolved = if name.ends_with("srcset") {
+        inline_srcset(ctx, &value)?
+      } else {
+        crate::get(ctx, &value)?.unwrap_or(value)
+      };
+      if !ctx.config.promote_lazy_attributes {
+        attributes.insert(name.as_str(), resolved);
+        continue;
+      }
+      attributes.remove(name.as_str());
+      match name.strip_prefix("data-").unwrap_or(name) {
+        "background" => {
+          let style = attributes.get("style").unwrap_or_default().trim_end();
+          let separator = if style.is_empty() || style.ends_with(';') {
+            ""
+          } else {
+            ";"
+          };
+          let style = format!("{}{}background-image:url('{}')", style, separator, resolved);
+          attributes.insert("style", style);
+        }
+        promoted => {
+          attributes.insert(promoted, resolved);
+        }
+      }
+    }
+  }
+
+  Ok(())
+}
+
+/// Inlines the attributes of `Config::extra_targets`.
+pub fn inline_extra_targets(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  for (selector, attribute) in ctx.config.extra_targets.clone() {
+    let targets: Vec<NodeRef> = document
+      .select(&selector)
+      .map_err(|()| crate::Error::InvalidSelector(selector.clone()))?
+      .map(|target| target.as_node().clone())
+      .collect();
+    for node in targets {
+      let element = node.as_element().unwrap();
+      let mut attributes = element.attributes.borrow_mut();
+      let value = match attributes.get(attribute.as_str()) {
+        Some(value) => value.to_string(),
+        None => continue,
+      };
+      log::debug!("[INLINER] inlining {} on {}", attribute, node.to_string());
+      let resolved = if attribute.ends_with("srcset") {
+        Some(inline_srcset(ctx, &value)?)
+      } else {
+        crate::get(ctx, &value)?
+      };
+      if let Some(resolved) = resolved {
+        if ctx.config.annotate_sources && !attribute.ends_with("srcset") {
+          attributes.insert(crate::ANNOTATION_ATTRIBUTE, value);
+        }
+        attributes.insert(attribute.as_str(), resolved);
+      }
+    }
+  }
+
+  Ok(())
+}
+
+/// Inlines the files of `<a href download>` up to `Config::max_download_size`, whatever their
+/// type and regardless of `Config::max_inline_size`.
+pub fn inline_downloads(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  let max_download_size = match ctx.config.max_download_size {
+    Some(max_download_size) => max_download_size,
+    None => return Ok(()),
+  };
+  let anchors: Vec<NodeRef> = document
+    .select("a[href][download]")
+    .unwrap()
+    .map(|anchor| anchor.as_node().clone())
+    .collect();
+
+  for node in anchors {
+    let element = node.as_element().unwrap();
+    let href = element.attributes.borrow().get("href").unwrap().to_string();
+    if href.trim().is_empty()
+      || href.starts_with('#')
+      || ["data:", "blob:", "javascript:", "mailto:"]
+        .iter()
+        .any(|scheme| href.starts_with(scheme))
+    {
+      continue;
+    }
+    let path = ctx.resolve(&href);
+    let path = path.split('#').next().unwrap_or_default().to_string();
+    if ctx.exclude.iter().any(|pattern| pattern.is_match(&path)) {
+      log::debug!("[INLINER] `{}` is excluded", path);
+      continue;
+    }
+    let raw = match crate::load_raw(ctx, &path) {
+      Ok(Some(raw)) => raw,
+      Ok(None) => continue,
+      Err(e) => {
+        log::error!("error loading {}: {:?}", path, e);
+        continue;
+      }
+    };
+    let content_type = crate::asset_content_type(&path).unwrap_or("application/octet-stream");
+    let data_uri = if raw.len() <= max_download_size {
+      Some(format!(
+        "data:{};base64,{}",
+        content_type,
+        crate::encode_base64(&raw)
+      ))
+    } else {
+      log::debug!(
+        "[INLINER] `{}` is greater than the max download size and will not be inlined",
+        path
+      );
+      None
+    };
+    let inlined = match &data_uri {
+      Some(data_uri) => ctx.reserve_output(&path, data_uri.len())?,
+      None => false,
+    };
+    if let Some(data_uri) = data_uri.as_ref().filter(|_| inlined) {
+      log::debug!("[INLINER] inlining download {}", href);
+      let mut attributes = element.attributes.borrow_mut();
+      // a data URI has no file name to default to
+      if attributes.get("download") == Some("") {
+        let name = path.rsplit(&['/', '\\'][..]).next().unwrap_or_default();
+        let name = percent_encoding::percent_decode_str(name).decode_utf8_lossy();
+        attributes.insert(
+          "download",
+          name.split('?').next().unwrap_or_default().to_string(),
+        );
+      }
+      if ctx.config.annotate_sources {
+        attributes.insert(crate::ANNOTATION_ATTRIBUTE, href.clone());
+      }
+      attributes.insert("href", data_uri.clone());
+    }
+    ctx.record(Asset {
+      path: path.clone(),
+      content_type: Some(content_type.to_string()),
+      size: data_uri.as_ref().map(|data_uri| data_uri.len()),
+      raw_size: Some(raw.len()),
+      inlined,
+    });
   }
 
   Ok(())
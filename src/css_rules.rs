@@ -0,0 +1,172 @@
+//! Splits stylesheets into rules and matches their selectors against the document, for the
+//! passes keeping only the rules the document uses.
+
+use once_cell::sync::Lazy;
+
+/// At-rules whose block holds rules, which are filtered in turn.
+static GROUPING_RULES: &[&str] = &["media", "supports", "layer", "container", "document"];
+
+/// A rule of a stylesheet.
+#[derive(Debug, PartialEq)]
+pub enum Rule<'a> {
+  /// A style rule, with its nested rules if any.
+  Style { selectors: &'a str, text: &'a str },
+  /// A grouping at-rule (`@media`, `@supports`, ...).
+  Group {
+    prelude: &'a str,
+    rules: Vec<Rule<'a>>,
+  },
+  /// Any other rule (`@font-face`, `@keyframes`, `@import`, ...), which has no selectors.
+  Other(&'a str),
+}
+
+/// The end of the string or of the comment starting at `start`.
+pub fn skip_string_or_comment(css: &[u8], start: usize) -> usize {
+  let mut i = start + 1;
+  if css[start] == b'/' {
+    // a comment
+    while i + 1 < css.len() && !(css[i] == b'*' && css[i + 1] == b'/') {
+      i += 1;
+    }
+    return (i + 2).min(css.len());
+  }
+  while i < css.len() && css[i] != css[start] {
+    if css[i] == b'\\' {
+      i += 1;
+    }
+    i += 1;
+  }
+  (i + 1).min(css.len())
+}
+
+/// The position of the first of the `delimiters` at the top level of `css`, after `start`.
+fn find_top_level(css: &[u8], start: usize, delimiters: &[u8]) -> Option<usize> {
+  let mut depth = 0;
+  let mut i = start;
+  while i < css.len() {
+    match css[i] {
+      b'"' | b'\'' => {
+        i = skip_string_or_comment(css, i);
+        continue;
+      }
+      b'/' if css.get(i + 1) == Some(&b'*') => {
+        i = skip_string_or_comment(css, i);
+        continue;
+      }
+      c if depth == 0 && delimiters.contains(&c) => return Some(i),
+      b'(' | b'[' | b'{' => depth += 1,
+      b')' | b']' | b'}' => depth -= 1,
+      _ => {}
+    }
+    i += 1;
+  }
+  None
+}
+
+/// Splits `css` into its rules.
+pub fn parse(css: &str) -> Vec<Rule<'_>> {
+  let bytes = css.as_bytes();
+  let mut rules = Vec::new();
+  let mut start = 0;
+  while start < bytes.len() {
+    if bytes[start].is_ascii_whitespace() {
+      start += 1;
+      continue;
+    }
+    let end = match find_top_level(bytes, start, b"{;") {
+      Some(end) if bytes[end] == b'{' => end,
+      Some(end) => {
+        rules.push(Rule::Other(&css[start..=end]));
+        start = end + 1;
+        continue;
+      }
+      None => {
+        rules.push(Rule::Other(&css[start..]));
+        break;
+      }
+    };
+    let prelude = css[start..end].trim();
+    // an unterminated block runs up to the end
+    let close = find_top_level(bytes, end + 1, b"}").unwrap_or(bytes.len());
+    let next = (close + 1).min(bytes.len());
+    let text = &css[start..next];
+    let rule = match prelude.strip_prefix('@') {
+      Some(at_rule) => {
+        let name = at_rule
+          .split(|c: char| c.is_whitespace() || c == '(')
+          .next()
+          .unwrap_or_default()
+          .to_ascii_lowercase();
+        if GROUPING_RULES.contains(&name.as_str()) {
+          Rule::Group {
+            prelude,
+            rules: parse(&css[end + 1..close]),
+          }
+        } else {
+          Rule::Other(text)
+        }
+      }
+      None => Rule::Style {
+        selectors: prelude,
+        text,
+      },
+    };
+    rules.push(rule);
+    start = next;
+  }
+  rules
+}
+
+/// Whether any of the comma separated `selectors` may match an element of `document`.
+/// Pseudo-elements and pseudo-classes are ignored, and selectors that can't be evaluated match.
+pub fn matches(document: &kuchiki::NodeRef, selectors: &str) -> bool {
+  static PSEUDO: Lazy<regex::Regex> =
+    Lazy::new(|| regex::Regex::new(r"::?[a-zA-Z-]+(?:\((?:[^()]|\([^()]*\))*\))?").unwrap());
+
+  let bytes = selectors.as_bytes();
+  let mut start = 0;
+  loop {
+    let end = find_top_level(bytes, start, b",").unwrap_or(bytes.len());
+    let selector = PSEUDO.replace_all(&selectors[start..end], "");
+    let selector = selector.trim();
+    let selector = if selector.is_empty() || selector.ends_with(['>', '+', '~']) {
+      format!("{}*", selector)
+    } else {
+      selector.to_string()
+    };
+    match document.select_first(&selector) {
+      Ok(_) => return true,
+      // a selector kuchiki can't parse can't be ruled out
+      Err(()) if document.select(&selector).is_err() => return true,
+      Err(()) => {}
+    }
+    if end == bytes.len() {
+      return false;
+    }
+    start = end + 1;
+  }
+}
+
+/// Serializes the `rules` for which `keep` is true, keeping the groups that still have rules.
+pub fn filter(rules: &[Rule<'_>], keep: &mut impl FnMut(&Rule<'_>) -> bool) -> String {
+  let mut css = String::new();
+  for rule in rules {
+    match rule {
+      Rule::Group { prelude, rules } => {
+        let inner = filter(rules, keep);
+        if !inner.is_empty() {
+          css.push_str(prelude);
+          css.push('{');
+          css.push_str(&inner);
+          css.push('}');
+        }
+      }
+      Rule::Style { text, .. } | Rule::Other(text) => {
+        if keep(rule) {
+          css.push_str(text);
+        }
+      }
+    }
+  }
+  css
+}
@@ -0,0 +1 @@
+console.log(__APP_VERSION__, __APP_VERSION_FULL__);
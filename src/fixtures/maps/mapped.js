@@ -0,0 +1,2 @@
+console.log("mapped");
+//# sourceMappingURL=mapped.js.map
@@ -0,0 +1,5 @@
+import { greet } from './greet.js';
+import { format } from "./util/format.js";
+import { ref } from 'vue';
+
+greet(format(ref('world')));
@@ -0,0 +1,2 @@
+export * from '../greet.js';
+export const format = (value) => String(value).trim();
@@ -0,0 +1,2 @@
+document.body.insertAdjacentHTML("beforeend", "<script>console.log(1)</SCRIPT>");
+var comment = '<!-- <script></script> -->';
@@ -0,0 +1 @@
+self.onmessage = (event) => postMessage(`echo: ${event.data}`);
@@ -0,0 +1,182 @@
+#[cfg(feature = "font-subset")]
+use std::collections::BTreeSet;
+#[cfg(feature = "woff2")]
+use std::convert::TryInto;
+
+#[cfg(feature = "font-subset")]
+use crate::FontSubset;
+
+/// Elements whose text isn't rendered.
+#[cfg(feature = "font-subset")]
+static HIDDEN_TEXT_ELEMENTS: &[&str] = &["script", "style"];
+/// Attributes whose value is rendered as text.
+#[cfg(feature = "font-subset")]
+static TEXT_ATTRIBUTES: &[&str] = &["alt", "title", "placeholder", "value"];
+
+/// The tags WOFF2 encodes with their index in this list.
+#[cfg(feature = "woff2")]
+static KNOWN_TAGS: &[&[u8; 4]] = &[
+  b"cmap", b"head", b"hhea", b"hmtx", b"maxp", b"name", b"OS/2", b"post", b"cvt ", b"fpgm",
+  b"glyf", b"loca", b"prep", b"CFF ", b"VORG", b"EBDT", b"EBLC", b"gasp", b"hdmx", b"kern",
+  b"LTSH", b"PCLT", b"VDMX", b"vhea", b"vmtx", b"BASE", b"GDEF", b"GPOS", b"GSUB", b"EBSC",
+  b"JSTF", b"MATH", b"CBDT", b"CBLC", b"COLR", b"CPAL", b"SVG ", b"sbix", b"acnt", b"avar",
+  b"bdat", b"bloc", b"bsln", b"cvar", b"fdsc", b"feat", b"fmtx", b"fvar", b"gvar", b"hsty",
+  b"just", b"lcar", b"mort", b"morx", b"opbd", b"prop", b"trak", b"Zapf", b"Silf", b"Glat",
+  b"Gloc", b"Feat", b"Sill",
+];
+
+/// Whether `extension` is an uncompressed (SFNT) font format, which can be subset and converted.
+pub fn is_sfnt(extension: Option<&str>) -> bool {
+  matches!(extension, Some("ttf") | Some("otf"))
+}
+
+/// The characters of the text of `document`, which the fonts are subset to.
+#[cfg(feature = "font-subset")]
+pub fn document_characters(document: &kuchiki::NodeRef) -> BTreeSet<char> {
+  let mut characters = BTreeSet::new();
+  for node in document.descendants() {
+    if let Some(text) = node.as_text() {
+      let hidden = node.parent().is_some_and(|parent| {
+        parent
+          .as_element()
+          .is_some_and(|element| HIDDEN_TEXT_ELEMENTS.contains(&&*element.name.local))
+      });
+      if !hidden {
+        characters.extend(text.borrow().chars());
+      }
+    } else if let Some(element) = node.as_element() {
+      let attributes = element.attributes.borrow();
+      for attribute in TEXT_ATTRIBUTES {
+        if let Some(value) = attributes.get(*attribute) {
+          characters.extend(value.chars());
+        }
+      }
+    }
+  }
+  characters
+}
+
+/// Reduces `font` to the glyphs of `characters` and of the `FontSubset::unicode_ranges`.
+/// The glyph ids are kept, only the outlines of the other glyphs and the layout tables are dropped.
+/// `None` if the font can't be parsed.
+#[cfg(feature = "font-subset")]
+pub fn subset(font: &[u8], characters: &BTreeSet<char>, config: &FontSubset) -> Option<Vec<u8>> {
+  let face = ttf_parser::Face::parse(font, 0).ok()?;
+  let ranges = config
+    .unicode_ranges
+    .iter()
+    .flat_map(|range| range.clone().filter_map(char::from_u32));
+  let glyphs: Vec<u16> = characters
+    .iter()
+    .copied()
+    .chain(ranges)
+    .filter_map(|c| face.glyph_index(c))
+    .map(|glyph| glyph.0)
+    .collect();
+  subsetter::subset(font, 0, subsetter::Profile::pdf(&glyphs)).ok()
+}
+
+#[cfg(feature = "woff2")]
+fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+  Some(u16::from_be_bytes(
+    bytes.get(offset..offset + 2)?.try_into().ok()?,
+  ))
+}
+
+#[cfg(feature = "woff2")]
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+  Some(u32::from_be_bytes(
+    bytes.get(offset..offset + 4)?.try_into().ok()?,
+  ))
+}
+
+/// Converts a TTF or OTF font to WOFF2. The tables are stored untransformed (the glyf and loca
+/// tables with the null transform) and compressed with Brotli.
+/// `None` if the font can't be parsed.
+#[cfg(feature = "woff2")]
+pub fn to_woff2(font: &[u8]) -> Option<Vec<u8>> {
+  let flavor = u32_at(font, 0)?;
+  let mut tables = (0..u16_at(font, 4)? as usize)
+    .map(|i| {
+      let record = 12 + i * 16;
+      let tag: [u8; 4] = font.get(record..record + 4)?.try_into().ok()?;
+      let offset = u32_at(font, record + 8)? as usize;
+      let length = u32_at(font, record + 12)? as usize;
+      Some((tag, font.get(offset..offset.checked_add(length)?)?))
+    })
+    .collect::<Option<Vec<_>>>()?;
+  // loca must directly follow glyf
+  tables.sort_by_key(|(tag, _)| match tag {
+    b"loca" => *b"glyg",
+    tag => *tag,
+  });
+
+  let mut directory = Vec::new();
+  let mut data = Vec::new();
+  for (tag, table) in &tables {
+    match KNOWN_TAGS.iter().position(|known| *known == tag) {
+      // version 3 is the null transform of glyf and loca, 0 the one of the other tables
+      Some(index @ 10..=11) => directory.push(index as u8 | 0xc0),
+      Some(index) => directory.push(index as u8),
+      None => {
+        directory.push(0x3f);
+        directory.extend_from_slice(tag);
+      }
+    }
+    push_base128(&mut directory, table.len() as u32);
+    data.extend_from_slice(table);
+  }
+  let sfnt_size: usize = 12
+    + tables.len() * 16
+    + tables
+      .iter()
+      .map(|(_, table)| (table.len() + 3) & !3)
+      .sum::<usize>();
+
+  let mut compressed = Vec::new();
+  let params = brotli::enc::BrotliEncoderParams {
+    quality: 11,
+    mode: brotli::enc::backward_references::BrotliEncoderMode::BROTLI_MODE_FONT,
+    ..Default::default()
+  };
+  brotli::BrotliCompress(&mut &data[..], &mut compressed, &params).ok()?;
+
+  let length = 48 + directory.len() + compressed.len();
+  let mut woff2 = Vec::with_capacity(length + 3);
+  woff2.extend_from_slice(b"wOF2");
+  woff2.extend_from_slice(&flavor.to_be_bytes());
+  woff2.extend_from_slice(&(((length + 3) & !3) as u32).to_be_bytes());
+  woff2.extend_from_slice(&(tables.len() as u16).to_be_bytes());
+  woff2.extend_from_slice(&[0, 0]);
+  woff2.extend_from_slice(&(sfnt_size as u32).to_be_bytes());
+  woff2.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+  // version 1.0, then no metadata nor private data
+  woff2.extend_from_slice(&[0, 1, 0, 0]);
+  woff2.extend_from_slice(&[0; 20]);
+  woff2.extend_from_slice(&directory);
+  woff2.extend_from_slice(&compressed);
+  woff2.resize((length + 3) & !3, 0);
+  Some(woff2)
+}
+
+/// Appends `value` as a WOFF2 `UIntBase128`: big-endian groups of 7 bits, the last one without the
+/// continuation bit.
+#[cfg(feature = "woff2")]
+fn push_base128(bytes: &mut Vec<u8>, value: u32) {
+  let groups = (0..5)
+    .rev()
+    .map(|i| (value >> (i * 7)) as u8 & 0x7f)
+    .skip_while(|group| *group == 0)
+    .collect::<Vec<_>>();
+  if groups.is_empty() {
+    bytes.push(0);
+    return;
+  }
+  let last = groups.len() - 1;
+  bytes.extend(
+    groups
+      .iter()
+      .enumerate()
+      .map(|(i, group)| if i == last { *group } else { group | 0x80 }),
+  );
+}
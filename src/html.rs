@@ -0,0 +1,577 @@
+use html5ever::QualName;
+use kuchiki::{traits::TendrilSink, NodeRef};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use url::Url;
+
+use crate::{
+  js_css, CommentPolicy, ConditionalComments, Context, CspNonce, PreloadPolicy, TransformLevel,
+  WarningKind,
+};
+
+/// The `'nonce-...'` sources of a `Content-Security-Policy`.
+static NONCE_SOURCE: Lazy<Regex> = Lazy::new(|| Regex::new(r"'nonce-[^']*'").unwrap());
+
+/// Elements whose text is whitespace sensitive or is code.
+static RAW_TEXT_ELEMENTS: &[&str] = &["pre", "textarea", "script", "style"];
+
+/// Whether a comment is an IE conditional comment (`<!--[if IE]>...<![endif]-->`),
+/// including both halves of the downlevel-revealed form (`<!--[if !IE]><!-->`, `<!--<![endif]-->`).
+fn is_conditional_comment(comment: &str) -> bool {
+  let comment = comment.trim();
+  comment.starts_with("[if") || comment.starts_with("<![endif]") || comment.ends_with("<![endif]")
+}
+
+/// Splits the downlevel-hidden conditional comment `[if lt IE 9]>body<![endif]` into its
+/// opening (`[if lt IE 9]>`) and its body.
+fn split_conditional_comment(comment: &str) -> Option<(&str, &str)> {
+  let comment = comment.strip_suffix("<![endif]")?;
+  if !comment.starts_with("[if") {
+    return None;
+  }
+  let opening_end = comment.find("]>")? + 2;
+  Some((&comment[..opening_end], &comment[opening_end..]))
+}
+
+/// Inlines the assets of IE conditional comments or removes them, see `ConditionalComments`.
+pub fn inline_conditional_comments(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  let policy = ctx.config.conditional_comments;
+  if policy == ConditionalComments::Keep {
+    return Ok(());
+  }
+  let comments: Vec<NodeRef> = document
+    .descendants()
+    .filter(|node| {
+      node
+        .as_comment()
+        .is_some_and(|comment| is_conditional_comment(&comment.borrow()))
+    })
+    .collect();
+
+  for comment in comments {
+    if policy == ConditionalComments::Strip {
+      log::debug!(
+        "[INLINER] removing conditional comment {}",
+        comment.to_string()
+      );
+      comment.detach();
+      continue;
+    }
+    let text = comment.as_comment().unwrap().borrow().clone();
+    let (opening, body) = match split_conditional_comment(&text) {
+      Some(parts) => parts,
+      None => continue,
+    };
+    let fragment =
+      kuchiki::parse_fragment(QualName::new(None, ns!(html), local_name!("div")), vec![]).one(body);
+    let root = match fragment.first_child() {
+      Some(root) => root,
+      None => continue,
+    };
+    crate::inline_assets(ctx, &root)?;
+    let body: String = root.children().map(|child| child.to_string()).collect();
+    // the comment would end early
+    if body.contains("-->") {
+      log::warn!(
+        "[INLINER] the inlined body of conditional comment `{}` contains `-->` and is left as is",
+        opening
+      );
+      continue;
+    }
+    *comment.as_comment().unwrap().borrow_mut() = format!("{}{}<![endif]", opening, body);
+  }
+  Ok(())
+}
+
+pub fn apply_comment_policy(ctx: &Context, document: &NodeRef) {
+  let keep_conditional = match ctx.config.comments {
+    CommentPolicy::Preserve => return,
+    CommentPolicy::Strip => false,
+    CommentPolicy::StripExceptConditional => true,
+  };
+
+  let comments: Vec<NodeRef> = document
+    .descendants()
+    .filter(|node| {
+      node
+        .as_comment()
+        .map(|comment| !(keep_conditional && is_conditional_comment(&comment.borrow())))
+        .unwrap_or(false)
+    })
+    .collect();
+  for comment in comments {
+    comment.detach();
+  }
+}
+
+/// The host of a URL, also accepting protocol-relative URLs (`//cdn.example.com`).
+fn host(url: &str) -> Option<String> {
+  let url = if url.starts_with("//") {
+    Url::parse(&format!("http:{}", url))
+  } else {
+    Url::parse(url)
+  };
+  url
+    .ok()
+    .and_then(|url| url.host_str().map(|host| host.to_string()))
+}
+
+pub fn remove_resource_hints(ctx: &Context, document: &NodeRef) {
+  if !ctx.config.remove_resource_hints {
+    return;
+  }
+
+  let hints: Vec<NodeRef> = document
+    .select("link[rel][href]")
+    .unwrap()
+    .filter(|link| {
+      let attributes = link.attributes.borrow();
+      let rel = attributes.get("rel").unwrap().to_lowercase();
+      let href = attributes.get("href").unwrap();
+      rel.split_whitespace().any(|rel| match rel {
+        "preload" | "modulepreload" | "prefetch" => {
+          let key = ctx.asset_key(href);
+          ctx.inlined.contains(&key) && !ctx.external.contains(&key)
+        }
+        // the connection is still needed if any asset from that host stays external
+        "preconnect" | "dns-prefetch" => {
+          let target = host(href);
+          target.is_some()
+            && ctx.inlined.iter().any(|key| host(key) == target)
+            && !ctx.external.iter().any(|key| host(key) == target)
+        }
+        _ => false,
+      })
+    })
+    .map(|link| link.as_node().clone())
+    .collect();
+  for hint in hints {
+    log::debug!("[INLINER] removing resource hint {}", hint.to_string());
+    hint.detach();
+  }
+}
+
+/// Handles the `<link rel="preload">` elements, see `PreloadPolicy`.
+pub fn inline_preloads(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  if ctx.config.preloads != PreloadPolicy::Inline {
+    return Ok(());
+  }
+
+  let preloads: Vec<NodeRef> = document
+    .select("link[rel][href], link[rel][imagesrcset]")
+    .unwrap()
+    .filter(|link| {
+      link
+        .attributes
+        .borrow()
+        .get("rel")
+        .unwrap()
+        .split_whitespace()
+        .any(|rel| rel.eq_ignore_ascii_case("preload") || rel.eq_ignore_ascii_case("modulepreload"))
+    })
+    .map(|link| link.as_node().clone())
+    .collect();
+  for preload in preloads {
+    let element = preload.as_element().unwrap();
+    let (href, destination, imagesrcset) = {
+      let attributes = element.attributes.borrow();
+      let is_module = attributes
+        .get("rel")
+        .unwrap()
+        .to_lowercase()
+        .contains("modulepreload");
+      (
+        attributes.get("href").map(|href| href.to_string()),
+        // a module preload is kept as long as its module stays external
+        if is_module {
+          "script".to_string()
+        } else {
+          attributes.get("as").unwrap_or_default().to_lowercase()
+        },
+        attributes
+          .get("imagesrcset")
+          .map(|imagesrcset| imagesrcset.to_string()),
+      )
+    };
+
+    // responsive image preloads (`imagesrcset`, `imagesizes`) pick a candidate like an `<img>`
+    if let Some(imagesrcset) = imagesrcset.filter(|_| destination == "image") {
+      log::debug!("[INLINER] inlining imagesrcset on {}", preload.to_string());
+      let imagesrcset = crate::binary::inline_srcset(ctx, &imagesrcset)?;
+      element
+        .attributes
+        .borrow_mut()
+        .insert("imagesrcset", imagesrcset);
+      if let Some(href) = href.filter(|href| !href.starts_with("data:")) {
+        if let Some(resolved) = crate::get(ctx, &href)? {
+          element.attributes.borrow_mut().insert("href", resolved);
+        }
+      }
+      continue;
+    }
+
+    let href = match href {
+      Some(href) if !href.starts_with("data:") => href,
+      _ => continue,
+    };
+    let key = ctx.asset_key(&href);
+    if ctx.inlined.contains(&key) && !ctx.external.contains(&key) {
+      log::debug!("[INLINER] removing preload of inlined `{}`", href);
+      preload.detach();
+    } else if destination == "image" || destination == "font" {
+      if let Some(resolved) = crate::get(ctx, &href)? {
+        log::debug!("[INLINER] inlining preload of `{}`", href);
+        let mut attributes = element.attributes.borrow_mut();
+        if ctx.config.annotate_sources {
+          attributes.insert(crate::ANNOTATION_ATTRIBUTE, href);
+        }
+        attributes.insert("href", resolved);
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Inlines the assets of `<noscript>` elements, whose content is parsed as text since scripts
+/// are enabled.
+pub fn inline_noscript(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  let noscripts: Vec<NodeRef> = document
+    .select("noscript")
+    .unwrap()
+    .map(|noscript| noscript.as_node().clone())
+    .collect();
+  for noscript in noscripts {
+    let text = noscript.text_contents();
+    if text.trim().is_empty() {
+      continue;
+    }
+    let fragment =
+      kuchiki::parse_fragment(QualName::new(None, ns!(html), local_name!("div")), vec![])
+        .one(text.as_str());
+    // the fragment's nodes are under its `<html>` element
+    let root = match fragment.first_child() {
+      Some(root) => root,
+      None => continue,
+    };
+    let serialize =
+      |root: &NodeRef| -> String { root.children().map(|child| child.to_string()).collect() };
+    let original = serialize(&root);
+    crate::inline_assets(ctx, &root)?;
+    let html = serialize(&root);
+    if html != original {
+      log::debug!("[INLINER] inlined the assets of a <noscript>");
+      for child in noscript.children().collect::<Vec<_>>() {
+        child.detach();
+      }
+      noscript.append(NodeRef::new_text(html));
+    }
+  }
+  Ok(())
+}
+
+/// Moves the content of `<template>` elements, a separate fragment the selectors don't reach
+/// and that isn't serialized, to their children so the passes process it like the rest of the
+/// document. The output is the same markup.
+pub fn expand_templates(document: &NodeRef) {
+  loop {
+    let contents: Vec<(NodeRef, NodeRef)> = document
+      .select("template")
+      .unwrap()
+      .filter_map(|template| {
+        let content = template.template_contents.clone()?;
+        content
+          .first_child()
+          .map(|_| (template.as_node().clone(), content))
+      })
+      .collect();
+    // nested templates are reached once their parent is expanded
+    if contents.is_empty() {
+      return;
+    }
+    for (template, content) in contents {
+      for child in content.children().collect::<Vec<_>>() {
+        template.append(child);
+      }
+    }
+  }
+}
+
+/// Applies the document's `<base href>`: a URL becomes the base of every relative reference,
+/// a path re-roots them (a leading `/` refers to the directory of the HTML file).
+pub fn apply_base(ctx: &mut Context, document: &NodeRef) {
+  let base = match document.select_first("base[href]") {
+    Ok(base) => base
+      .attributes
+      .borrow()
+      .get("href")
+      .unwrap()
+      .trim()
+      .to_string(),
+    Err(()) => return,
+  };
+  if let Ok(url) = Url::parse(&base) {
+    log::debug!("[INLINER] resolving relative references against {}", url);
+    ctx.base_url.replace(url);
+  } else if !base.is_empty() {
+    ctx.root_path = ctx.root_path.join(base.trim_start_matches('/'));
+    log::debug!(
+      "[INLINER] resolving relative paths against {}",
+      ctx.root_path.display()
+    );
+  }
+}
+
+/// Replaces the `src` of iframes with a `srcdoc` holding the inlined document,
+/// up to `Config::max_iframe_depth` levels.
+pub fn inline_iframes(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  if ctx.iframe_depth >= ctx.config.max_iframe_depth {
+    return Ok(());
+  }
+  let iframes: Vec<NodeRef> = document
+    .select("iframe[src]:not([srcdoc])")
+    .unwrap()
+    .map(|iframe| iframe.as_node().clone())
+    .collect();
+  for iframe in iframes {
+    let element = iframe.as_element().unwrap();
+    let src = element.attributes.borrow().get("src").unwrap().to_string();
+    if src.starts_with("data:") || src.starts_with("about:") || src.starts_with("javascript:") {
+      continue;
+    }
+    log::debug!("[INLINER] inlining iframe {}", iframe.to_string());
+    // a srcdoc document has no URL to keep the fragment in
+    let path = src.split('#').next().unwrap_or_default();
+    if let Some(srcdoc) = crate::inline_nested_document(ctx, path)? {
+      let mut attributes = element.attributes.borrow_mut();
+      attributes.remove("src");
+      // the serializer escapes the attribute value
+      attributes.insert("srcdoc", srcdoc);
+      if ctx.config.annotate_sources {
+        attributes.insert(crate::ANNOTATION_ATTRIBUTE, src);
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Whether the `Content-Security-Policy` of `document` allows scripts and styles by nonce: one of
+/// its `<meta>` elements has a `'nonce-...'` source, or one of its elements carries a nonce (e.g.
+/// for a policy sent as a header).
+pub fn uses_csp_nonces(document: &NodeRef) -> bool {
+  document.select_first("script[nonce], style[nonce]").is_ok()
+    || document
+      .select(r#"meta[http-equiv][content]"#)
+      .unwrap()
+      .any(|meta| {
+        let attributes = meta.attributes.borrow();
+        is_csp_meta(&attributes) && NONCE_SOURCE.is_match(attributes.get("content").unwrap())
+      })
+}
+
+fn is_csp_meta(attributes: &kuchiki::Attributes) -> bool {
+  attributes
+    .get("http-equiv")
+    .is_some_and(|name| name.eq_ignore_ascii_case("content-security-policy"))
+}
+
+/// Whether the element referencing the script or stylesheet at `path` may be inlined as far as the
+/// document's `Content-Security-Policy` is concerned: without a nonce, the browser would block it
+/// when the policy relies on nonces.
+pub fn allows_inlining(ctx: &mut Context, attributes: &kuchiki::Attributes, path: &str) -> bool {
+  if !ctx.csp_nonces || attributes.contains("nonce") {
+    return true;
+  }
+  let message = format!(
+    "`{}` has no nonce while the Content-Security-Policy relies on nonces; it was left external",
+    path
+  );
+  ctx.warn(WarningKind::MissingNonce, message);
+  false
+}
+
+/// Applies the nonce of `Config::csp_nonce` to the elements created or replaced while inlining.
+pub fn apply_csp_nonce(ctx: &mut Context, document: &NodeRef, roots: &[NodeRef]) {
+  let nonce = match &ctx.config.csp_nonce {
+    CspNonce::Document => document
+      .select("script[nonce], style[nonce]")
+      .unwrap()
+      .find_map(|element| {
+        let attributes = element.attributes.borrow();
+        attributes.get("nonce").map(str::to_string)
+      }),
+    CspNonce::Value(nonce) => Some(nonce.clone()),
+    CspNonce::Random => Some(ctx.rng.alphanumeric(24)),
+  };
+  let generated = std::mem::take(&mut ctx.generated);
+  let nonce = match nonce {
+    Some(nonce) => nonce,
+    None => return,
+  };
+  let replace = ctx.config.csp_nonce != CspNonce::Document;
+
+  for element in &generated {
+    // the element may have been merged into another one or removed since
+    if element.parent().is_none() {
+      continue;
+    }
+    if let Some(element) = element.as_element() {
+      let mut attributes = element.attributes.borrow_mut();
+      if replace || !attributes.contains("nonce") {
+        attributes.insert("nonce", nonce.clone());
+      }
+    }
+  }
+  if !replace {
+    log::debug!("[INLINER] applied the nonce `{}`", nonce);
+    return;
+  }
+  for root in roots {
+    // the elements the previous nonce allowed are allowed by the new one
+    for element in root.select("script[nonce], style[nonce]").unwrap() {
+      element
+        .attributes
+        .borrow_mut()
+        .insert("nonce", nonce.clone());
+    }
+    for meta in root.select("meta[http-equiv][content]").unwrap() {
+      let mut attributes = meta.attributes.borrow_mut();
+      if !is_csp_meta(&attributes) {
+        continue;
+      }
+      let policy = attributes.get("content").unwrap_or_default();
+      let source = format!("'nonce-{}'", nonce);
+      let policy = NONCE_SOURCE
+        .replace_all(policy, regex::NoExpand(&source))
+        .into_owned();
+      attributes.insert("content", policy);
+    }
+  }
+  log::debug!("[INLINER] applied the nonce `{}`", nonce);
+}
+
+pub fn remove_base(ctx: &Context, document: &NodeRef) {
+  if !ctx.config.remove_base {
+    return;
+  }
+  // the remaining external references still rely on the base
+  if !ctx.external.is_empty() {
+    log::debug!("[INLINER] keeping <base> since some assets were not inlined");
+    return;
+  }
+  if let Ok(base) = document.select_first("base") {
+    base.as_node().detach();
+  }
+}
+
+/// Attributes holding a subresource URL; `href` only loads a subresource on `<link>`.
+static SUBRESOURCE_ATTRIBUTES: &[&str] = &["src", "poster", "data"];
+
+/// Finds the `http` URLs in CSS `url()` references.
+static INSECURE_CSS_URL: Lazy<Regex> =
+  Lazy::new(|| Regex::new(r#"(url\(\s*["']?)http://([^"')]+)"#).unwrap());
+
+/// Reports (or upgrades) the `http` references left in a document served over `https`,
+/// which webviews tend to block silently.
+pub fn check_mixed_content(ctx: &mut Context, document: &NodeRef) {
+  if ctx.base_url.as_ref().map(|url| url.scheme()) != Some("https") {
+    return;
+  }
+  let upgrade = ctx.config.upgrade_insecure_requests;
+
+  let mut insecure = Vec::new();
+  // collected first since upgrading a `<style>` replaces its text
+  let elements: Vec<_> = document.select("*").unwrap().collect();
+  for element in elements {
+    let name = element.name.local.to_string();
+    let mut attributes = element.attributes.borrow_mut();
+
+    let mut names: Vec<&str> = SUBRESOURCE_ATTRIBUTES.to_vec();
+    if name == "link" {
+      names.push("href");
+    }
+    for attribute in names {
+      if let Some(value) = attributes.get(attribute) {
+        if let Some(rest) = value.strip_prefix("http://") {
+          insecure.push(value.to_string());
+          if upgrade {
+            let upgraded = format!("https://{}", rest);
+            attributes.insert(attribute, upgraded);
+          }
+        }
+      }
+    }
+
+    let mut check_css = |css: &str| {
+      for caps in INSECURE_CSS_URL.captures_iter(css) {
+        insecure.push(format!("http://{}", &caps[2]));
+      }
+      if upgrade {
+        Some(
+          INSECURE_CSS_URL
+            .replace_all(css, "${1}https://${2}")
+            .to_string(),
+        )
+      } else {
+        None
+      }
+    };
+    if let Some(upgraded) = attributes.get("style").and_then(&mut check_css) {
+      attributes.insert("style", upgraded);
+    }
+    if name == "style" {
+      let node = element.as_node();
+      if let Some(upgraded) = check_css(&node.text_contents()) {
+        for child in node.children() {
+          child.detach();
+        }
+        node.append(NodeRef::new_text(upgraded));
+      }
+    }
+  }
+
+  for url in insecure {
+    if upgrade {
+      log::debug!("[INLINER] upgraded `{}` to https", url);
+    } else {
+      let message = format!("`{}` is loaded over http in an https document", url);
+      ctx.warn(WarningKind::MixedContent, message);
+    }
+  }
+}
+
+fn enclosing_raw_text_element(node: &NodeRef) -> Option<String> {
+  node.ancestors().find_map(|ancestor| {
+    ancestor
+      .as_element()
+      .map(|element| element.name.local.to_string())
+      .filter(|name| RAW_TEXT_ELEMENTS.contains(&name.as_str()))
+  })
+}
+
+pub fn minify_whitespace(ctx: &Context, document: &NodeRef) {
+  static SPACES: Lazy<Regex> = Lazy::new(|| Regex::new(r"( {2,})").unwrap());
+  static WHITESPACE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\s+)").unwrap());
+
+  let mode = ctx.config.transform_level;
+  if mode == TransformLevel::None {
+    return;
+  }
+
+  for node in document.descendants() {
+    if let Some(text) = node.as_text() {
+      let regex = match (mode, enclosing_raw_text_element(&node).as_deref()) {
+        (TransformLevel::Safe, None) => &SPACES,
+        (TransformLevel::Aggressive, None) => &WHITESPACE,
+        // the strings of the stylesheets are kept; scripts are left to the `js-minify` feature
+        (TransformLevel::Aggressive, Some("style")) => {
+          let minified = js_css::collapse_whitespace(&text.borrow());
+          *text.borrow_mut() = minified;
+          continue;
+        }
+        _ => continue,
+      };
+      let minified = regex.replace_all(&text.borrow(), " ").to_string();
+      *text.borrow_mut() = minified;
+    }
+  }
+}
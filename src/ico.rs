@@ -0,0 +1,159 @@
+/// The signature every PNG file starts with.
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// An image of an ICO file.
+struct Entry<'a> {
+  width: u32,
+  height: u32,
+  bit_count: u16,
+  data: &'a [u8],
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+  Some(u16::from_le_bytes([
+    *bytes.get(offset)?,
+    *bytes.get(offset + 1)?,
+  ]))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+  Some(u32::from_le_bytes([
+    *bytes.get(offset)?,
+    *bytes.get(offset + 1)?,
+    *bytes.get(offset + 2)?,
+    *bytes.get(offset + 3)?,
+  ]))
+}
+
+/// The images of an ICO file, `None` if it's malformed.
+fn entries(ico: &[u8]) -> Option<Vec<Entry<'_>>> {
+  if u16_at(ico, 0)? != 0 || u16_at(ico, 2)? != 1 {
+    return None;
+  }
+  (0..u16_at(ico, 4)? as usize)
+    .map(|i| {
+      let entry = 6 + i * 16;
+      // a dimension of 0 stands for 256
+      let dimension = |offset| match *ico.get(entry + offset)? {
+        0 => Some(256),
+        size => Some(size as u32),
+      };
+      let size = u32_at(ico, entry + 8)? as usize;
+      let offset = u32_at(ico, entry + 12)? as usize;
+      Some(Entry {
+        width: dimension(0)?,
+        height: dimension(1)?,
+        bit_count: u16_at(ico, entry + 6)?,
+        data: ico.get(offset..offset.checked_add(size)?)?,
+      })
+    })
+    .collect()
+}
+
+/// The largest image of an ICO file as a PNG, converted from its bitmap if needed.
+/// `None` if the file is malformed or the image is a bitmap other than a 32-bit one.
+pub fn largest_png(ico: &[u8]) -> Option<Vec<u8>> {
+  let entries = entries(ico)?;
+  let largest = entries
+    .iter()
+    .max_by_key(|entry| (entry.width * entry.height, entry.bit_count))?;
+  if largest.data.starts_with(PNG_SIGNATURE) {
+    Some(largest.data.to_vec())
+  } else {
+    bitmap_to_png(largest.data)
+  }
+}
+
+/// Converts a 32-bit DIB (a bitmap without its file header, as stored in ICO files) to a PNG.
+fn bitmap_to_png(dib: &[u8]) -> Option<Vec<u8>> {
+  let header_size = u32_at(dib, 0)? as usize;
+  let width = u32_at(dib, 4)? as usize;
+  // the height covers both the color and the AND mask
+  let height = u32_at(dib, 8)? as usize / 2;
+  if u16_at(dib, 14)? != 32 || u32_at(dib, 16)? != 0 {
+    return None;
+  }
+  if width == 0 || height == 0 {
+    return None;
+  }
+  let end = width
+    .checked_mul(height)?
+    .checked_mul(4)?
+    .checked_add(header_size)?;
+  let pixels = dib.get(header_size..end)?;
+  // old icons leave the alpha channel empty and rely on the AND mask
+  let opaque = pixels.chunks(4).all(|pixel| pixel[3] == 0);
+
+  // rows are stored bottom-up in BGRA
+  let mut image = Vec::with_capacity(height * (width * 4 + 1));
+  for row in pixels.chunks(width * 4).rev() {
+    image.push(0);
+    for pixel in row.chunks(4) {
+      let alpha = if opaque { 0xff } else { pixel[3] };
+      image.extend_from_slice(&[pixel[2], pixel[1], pixel[0], alpha]);
+    }
+  }
+
+  let mut header = Vec::with_capacity(13);
+  header.extend_from_slice(&(width as u32).to_be_bytes());
+  header.extend_from_slice(&(height as u32).to_be_bytes());
+  // 8 bits per channel, RGBA, default compression, filter and interlacing
+  header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+  let mut png = PNG_SIGNATURE.to_vec();
+  png_chunk(&mut png, b"IHDR", &header);
+  png_chunk(&mut png, b"IDAT", &zlib_stored(&image));
+  png_chunk(&mut png, b"IEND", &[]);
+  Some(png)
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+  png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+  let start = png.len();
+  png.extend_from_slice(kind);
+  png.extend_from_slice(data);
+  let crc = crc32(&png[start..]);
+  png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// A zlib stream of uncompressed blocks; the data URI is smaller than the ICO anyway.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+  let mut stream = vec![0x78, 0x01];
+  let mut blocks = data.chunks(0xffff).peekable();
+  if blocks.peek().is_none() {
+    stream.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+  }
+  while let Some(block) = blocks.next() {
+    stream.push(blocks.peek().is_none() as u8);
+    let len = block.len() as u16;
+    stream.extend_from_slice(&len.to_le_bytes());
+    stream.extend_from_slice(&(!len).to_le_bytes());
+    stream.extend_from_slice(block);
+  }
+  stream.extend_from_slice(&adler32(data).to_be_bytes());
+  stream
+}
+
+fn crc32(data: &[u8]) -> u32 {
+  let mut crc = !0u32;
+  for byte in data {
+    crc ^= *byte as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 == 1 {
+        (crc >> 1) ^ 0xedb8_8320
+      } else {
+        crc >> 1
+      };
+    }
+  }
+  !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+  let (mut a, mut b) = (1u32, 0u32);
+  for byte in data {
+    a = (a + *byte as u32) % 65521;
+    b = (b + a) % 65521;
+  }
+  (b << 16) | a
+}
@@ -1,23 +1,146 @@
+use html5ever::QualName;
+use kuchiki::{Attributes, ElementData, NodeDataRef, NodeRef};
+use once_cell::sync::Lazy;
+use regex::Captures;
+
 use std::{
-  collections::HashMap,
-  path::{Path, PathBuf},
+  collections::{hash_map::DefaultHasher, HashMap},
+  hash::{Hash, Hasher},
+  path::{Component, Path, PathBuf},
 };
 
-use html5ever::QualName;
-use kuchiki::NodeRef;
-use regex::Captures;
+use crate::{
+  css_rules::{self, Rule},
+  html, Context, DeferredScripts, DuplicateScripts, IntegrityPolicy, NomodulePolicy, OversizedUrls,
+  ServiceWorkers, SourceMapPolicy, TransformLevel, WarningKind,
+};
 
-pub fn inline_script_link(
-  mut cache: &mut HashMap<String, Option<String>>,
-  config: &super::Config,
-  root_path: &PathBuf,
-  document: &NodeRef,
-) -> crate::Result<()> {
-  let mut targets = vec![];
-  for target in document
-    .select("script, style, link, *:not(svg)[style]")
+/// Attributes dropped from a `<script>` once its source is inlined.
+pub static SCRIPT_SKIPPED_ATTRIBUTES: &[&str] = &["src", "integrity", "crossorigin"];
+/// Types of the `<script>` elements holding JSON data, whose `src` is inlined as their text.
+static JSON_SCRIPT_TYPES: &[&str] = &["application/ld+json", "speculationrules"];
+/// Attribute of the `<style>` elements deferred by `Config::critical_css`, holding their media.
+static DEFERRED_MEDIA_ATTRIBUTE: &str = "data-deferred-media";
+/// Applies the deferred `<style>` elements once the document is loaded.
+static DEFERRED_STYLES_LOADER: &str = r#"addEventListener("load",function(){document.querySelectorAll("style[data-deferred-media]").forEach(function(s){s.media=s.getAttribute("data-deferred-media")})})"#;
+/// Attributes dropped when a `<link>` is converted to a `<style>`; the other ones (`media`,
+/// `title`, `disabled`, ...) are carried over.
+static LINK_SKIPPED_ATTRIBUTES: &[&str] = &["href", "rel", "integrity", "crossorigin"];
+
+/// Creates a `name` element holding `text`, carrying over the `attributes` not listed in `skipped`.
+fn replacement_element(
+  name: &str,
+  attributes: &Attributes,
+  skipped: &[&str],
+  text: String,
+) -> NodeRef {
+  let attributes = attributes
+    .map
+    .iter()
+    .filter(|(name, _)| !skipped.contains(&&*name.local))
+    .map(|(name, attribute)| (name.clone(), attribute.clone()));
+  let node = NodeRef::new_element(QualName::new(None, ns!(html), name.into()), attributes);
+  node.append(NodeRef::new_text(escape_raw_text(&text, name)));
+  node
+}
+
+/// Escapes the `</{name}` sequences of the text of a `name` element, which would end it early, as
+/// `<\/{name}`, and for scripts the `<!--` sequences, which would change how the parser looks for
+/// the end tag, as `<\!--`: a CSS or JS escape wherever they may legitimately appear (strings,
+/// URLs, regular expressions).
+pub fn escape_raw_text(text: &str, name: &str) -> String {
+  let mut escaped = String::with_capacity(text.len());
+  let mut last = 0;
+  for (start, _) in text.match_indices('<') {
+    let rest = &text[start + 1..];
+    let end_tag = rest.starts_with('/')
+      && rest
+        .get(1..name.len() + 1)
+        .is_some_and(|tag| tag.eq_ignore_ascii_case(name));
+    if end_tag || (name == "script" && rest.starts_with("!--")) {
+      escaped.push_str(&text[last..start + 1]);
+      escaped.push('\\');
+      last = start + 1;
+    }
+  }
+  escaped.push_str(&text[last..]);
+  escaped
+}
+
+fn content_hash(content: &str) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  content.hash(&mut hasher);
+  hasher.finish()
+}
+
+/// Whether `script` starts with a `"use strict"` directive, which can't apply to a part of a
+/// concatenated script.
+fn is_strict(script: &str) -> bool {
+  let script = script.trim_start();
+  script.starts_with("\"use strict\"") || script.starts_with("'use strict'")
+}
+
+/// Appends the inlined `script` to `previous` and detaches it when `previous` is the element right
+/// before it with the same attributes, see `Config::concat_scripts`.
+fn concat_script(previous: &NodeRef, script: &NodeRef) -> bool {
+  let mut sibling = script.previous_sibling();
+  while let Some(text) = sibling.clone().filter(|node| {
+    node
+      .as_text()
+      .is_some_and(|text| text.borrow().trim().is_empty())
+  }) {
+    sibling = text.previous_sibling();
+  }
+  if sibling.as_ref() != Some(previous) {
+    return false;
+  }
+  let attributes = |node: &NodeRef| {
+    let mut attributes = node.as_element().unwrap().attributes.borrow().clone();
+    attributes.remove(crate::ANNOTATION_ATTRIBUTE);
+    attributes
+  };
+  let (previous_text, text) = (previous.text_contents(), script.text_contents());
+  if attributes(previous) != attributes(script) || is_strict(&previous_text) || is_strict(&text) {
+    return false;
+  }
+
+  log::debug!("[INLINER] concatenating an inlined script with the previous one");
+  let previous_element = previous.as_element().unwrap();
+  let source = script
+    .as_element()
     .unwrap()
-  {
+    .attributes
+    .borrow()
+    .get(crate::ANNOTATION_ATTRIBUTE)
+    .map(str::to_string);
+  if let Some(source) = source {
+    let mut attributes = previous_element.attributes.borrow_mut();
+    let sources = match attributes.get(crate::ANNOTATION_ATTRIBUTE) {
+      Some(sources) => format!("{} {}", sources, source),
+      None => source,
+    };
+    attributes.insert(crate::ANNOTATION_ATTRIBUTE, sources);
+  }
+  for child in previous.children() {
+    child.detach();
+  }
+  // the line break ends a trailing line comment, the semicolon a statement left open
+  previous.append(NodeRef::new_text(format!(
+    "{}\n;{}",
+    previous_text.trim_end(),
+    text
+  )));
+  script.detach();
+  true
+}
+
+pub fn inline_script_link(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  let has_module_scripts = document.select_first(r#"script[type="module"]"#).is_ok();
+  // the last inlined script, which the next one may be concatenated to
+  let mut concatenated: Option<NodeRef> = None;
+
+  let mut targets = vec![];
+  for target in document.select("script, style, link, [style]").unwrap() {
     targets.push(target);
   }
 
@@ -28,23 +151,122 @@ pub fn inline_script_link(
     match element.name.local.to_string().as_str() {
       "script" => {
         let attrs = element.attributes.borrow_mut();
-        // if the script is a defer script or its type is not text/javascript, we won't inline it
-        if attrs.get("defer").is_some()
-          || attrs.get("type").unwrap_or("text/javascript") != "text/javascript"
+        if has_module_scripts
+          && (ctx.config.nomodule == NomodulePolicy::Remove || !ctx.config.target.runs_nomodule())
+          && attrs.contains("nomodule")
+        {
+          let message = format!(
+            "removed nomodule script `{}` in favor of the module scripts",
+            attrs.get("src").unwrap_or("<inline>")
+          );
+          ctx.warn(WarningKind::NomoduleRemoved, message);
+          node.detach();
+          continue;
+        }
+        let script_type = attrs.get("type");
+        if let Some(source) = attrs.get("src").filter(|_| {
+          script_type.is_some_and(|script_type| {
+            JSON_SCRIPT_TYPES.contains(&script_type.trim().to_ascii_lowercase().as_str())
+          })
+        }) {
+          if !html::allows_inlining(ctx, &attrs, source) {
+            continue;
+          }
+          if let Some(json) = crate::get_verified(ctx, source, attrs.get("integrity"))? {
+            log::debug!("[INLINER] inlining the JSON script `{}`", source);
+            // `<` only appears in the JSON strings, where it may be escaped
+            let json = json.trim_end().replace('<', "\\u003c");
+            let replacement =
+              replacement_element("script", &attrs, SCRIPT_SKIPPED_ATTRIBUTES, json);
+            ctx.annotate(&replacement, source);
+            ctx.generate(&replacement);
+            node.insert_after(replacement);
+            node.detach();
+          }
+          continue;
+        }
+        // module scripts are left to `Config::bundle_modules`, the other types don't hold JS
+        if !is_javascript(script_type)
+          || script_type
+            .is_some_and(|script_type| script_type.trim().eq_ignore_ascii_case("module"))
         {
           continue;
         }
+        let deferred = attrs.contains("defer");
 
         if let Some(source) = attrs.get("src") {
           log::debug!("[INLINER] inlining src on {}", node.to_string());
+          if !html::allows_inlining(ctx, &attrs, source) {
+            continue;
+          }
+
+          if let Some(script) = crate::get_verified(ctx, source, attrs.get("integrity"))? {
+            let script = apply_source_map_policy(ctx, script, &ctx.resolve(source))?;
+            let script = compress_js(ctx.config.transform_level, script);
+            let hash = content_hash(&script);
+            if let Some(first) = ctx.inlined_scripts.get(&hash).cloned() {
+              match ctx.config.duplicate_scripts {
+                DuplicateScripts::Inline => {}
+                DuplicateScripts::Remove => {
+                  log::debug!("[INLINER] removing duplicate script `{}`", source);
+                  node.detach();
+                  continue;
+                }
+                DuplicateScripts::Comment => {
+                  log::debug!("[INLINER] replacing duplicate script `{}`", source);
+                  node.insert_after(NodeRef::new_comment(format!(
+                    " `{}` duplicates `{}`, inlined above ",
+                    source, first
+                  )));
+                  node.detach();
+                  continue;
+                }
+              }
+            } else {
+              ctx.inlined_scripts.insert(hash, source.to_string());
+            }
 
-          if let Some(script) = crate::get(&mut cache, &source, &config, &root_path)? {
-            let replacement_node =
-              NodeRef::new_element(QualName::new(None, ns!(html), "script".into()), None);
-            replacement_node.append(NodeRef::new_text(script));
+            if deferred && ctx.config.deferred_scripts == DeferredScripts::DataUri {
+              // the script stays deferred, running in order with the other deferred scripts
+              let data_uri = format!(
+                "data:text/javascript,{}",
+                percent_encoding::utf8_percent_encode(
+                  script.trim_end(),
+                  crate::modules::MODULE_DATA_URI_ESCAPES
+                )
+              );
+              let replacement =
+                replacement_element("script", &attrs, SCRIPT_SKIPPED_ATTRIBUTES, String::new());
+              if let Some(element) = replacement.as_element() {
+                element.attributes.borrow_mut().insert("src", data_uri);
+              }
+              ctx.annotate(&replacement, source);
+              ctx.generate(&replacement);
+              node.insert_after(replacement);
+              node.detach();
+              continue;
+            }
 
-            node.insert_after(replacement_node);
+            let mut skipped = SCRIPT_SKIPPED_ATTRIBUTES.to_vec();
+            if deferred {
+              skipped.push("defer");
+            }
+            let replacement = replacement_element("script", &attrs, &skipped, script);
+            ctx.annotate(&replacement, source);
+            ctx.generate(&replacement);
+            match document.select_first("body").ok().filter(|_| deferred) {
+              // inline scripts can't be deferred: they run once the body is parsed instead, in
+              // the order of the deferred scripts
+              Some(body) => body.as_node().append(replacement.clone()),
+              None => node.insert_after(replacement.clone()),
+            }
             node.detach();
+            if ctx.config.concat_scripts && !attrs.contains("async") {
+              concatenated = match concatenated.take() {
+                Some(previous) if concat_script(&previous, &replacement) => Some(previous),
+                _ => Some(replacement),
+              };
+            }
           }
         } else {
           continue;
@@ -52,25 +274,17 @@ pub fn inline_script_link(
       }
       "style" => {
         let css = node.text_contents();
-        match inline_css(
-          &mut cache,
-          Some(css),
-          root_path
-            .clone()
-            .into_os_string()
-            .into_string()
-            .unwrap()
-            .as_str(),
-          &config,
-          &root_path,
-        ) {
+        let css_path = ctx.document_base();
+        match inline_stylesheet(ctx, Some(css), &css_path) {
           Ok(css) => {
             if let Some(css) = css {
-              let replacement_node =
-                NodeRef::new_element(QualName::new(None, ns!(html), "style".into()), None);
-              replacement_node.append(NodeRef::new_text(css));
-
-              node.insert_after(replacement_node);
+              let attributes = element.attributes.borrow();
+              let replacement = replacement_element("style", &attributes, &[], css);
+              // a style without a nonce stays blocked by a policy relying on nonces
+              if !ctx.csp_nonces || attributes.contains("nonce") {
+                ctx.generate(&replacement);
+              }
+              node.insert_after(replacement);
               node.detach();
             }
           }
@@ -78,28 +292,50 @@ pub fn inline_script_link(
         }
       }
       "link" => {
-        let css_path = {
+        let (css_path, is_alternate) = {
           let text_attr = element.attributes.borrow_mut();
+          let rel = text_attr.get("rel").unwrap_or_default().to_lowercase();
+          let rel: Vec<&str> = rel.split_whitespace().collect();
           let out = if let Some(c) = text_attr
-            .get("rel")
-            .filter(|rel| *rel == "stylesheet")
-            .and(text_attr.get("href"))
+            .get("href")
+            .filter(|_| rel.contains(&"stylesheet"))
           {
-            String::from(c)
+            ctx.resolve(c)
           } else {
             continue;
           };
-          out
+          (out, rel.contains(&"alternate"))
         };
+        if !html::allows_inlining(ctx, &element.attributes.borrow(), &css_path) {
+          continue;
+        }
+        let integrity = element
+          .attributes
+          .borrow()
+          .get("integrity")
+          .map(str::to_string);
 
-        match inline_css_path(&mut cache, &css_path, &config, &root_path) {
+        match inline_css_path(ctx, &css_path, integrity.as_deref()) {
           Ok(css) => {
             if let Some(css) = css {
-              let replacement_node =
-                NodeRef::new_element(QualName::new(None, ns!(html), "style".into()), None);
-              replacement_node.append(NodeRef::new_text(css));
-
-              node.insert_after(replacement_node);
+              let replacement = replacement_element(
+                "style",
+                &element.attributes.borrow(),
+                LINK_SKIPPED_ATTRIBUTES,
+                css,
+              );
+              // an alternate stylesheet stays off until a style switcher enables it by its `title`
+              if is_alternate {
+                if let Some(style) = replacement.as_element() {
+                  style
+                    .attributes
+                    .borrow_mut()
+                    .insert("disabled", String::new());
+                }
+              }
+              ctx.annotate(&replacement, &css_path);
+              ctx.generate(&replacement);
+              node.insert_after(replacement);
               node.detach();
             }
           }
@@ -110,18 +346,8 @@ pub fn inline_script_link(
         let mut attrs = element.attributes.borrow_mut();
         if let Some(style) = attrs.get("style") {
           log::debug!("[INLINER] inlining style on {}", node.to_string());
-          match inline_css(
-            &mut cache,
-            Some(style.to_string()),
-            root_path
-              .clone()
-              .into_os_string()
-              .into_string()
-              .unwrap()
-              .as_str(),
-            &config,
-            &root_path,
-          ) {
+          let css_path = ctx.document_base();
+          match inline_css(ctx, Some(style.to_string()), &css_path) {
             Ok(Some(css)) => {
               attrs.insert("style", css);
             }
@@ -136,128 +362,1625 @@ pub fn inline_script_link(
   Ok(())
 }
 
-fn inline_css_path<P: AsRef<Path>>(
-  mut cache: &mut HashMap<String, Option<String>>,
+/// Whether the asset at `path`, loaded as `raw`, may be inlined as far as the `integrity` metadata
+/// of the element referencing it is concerned, see `Config::integrity`.
+pub fn verify_integrity(ctx: &mut Context, integrity: &str, path: &str, raw: &[u8]) -> bool {
+  use sha2::Digest;
+
+  if ctx.config.integrity == IntegrityPolicy::Ignore {
+    return true;
+  }
+  // the browser only checks the hashes of the strongest algorithm, the options are ignored
+  let hashes: Vec<(&str, &str)> = integrity
+    .split_whitespace()
+    .filter_map(|metadata| metadata.split('?').next()?.split_once('-'))
+    .filter(|(algorithm, _)| matches!(*algorithm, "sha256" | "sha384" | "sha512"))
+    .collect();
+  let strongest = match hashes.iter().map(|(algorithm, _)| *algorithm).max() {
+    Some(strongest) => strongest,
+    None => return true,
+  };
+  let digest = crate::encode_base64(&match strongest {
+    "sha256" => sha2::Sha256::digest(raw).to_vec(),
+    "sha384" => sha2::Sha384::digest(raw).to_vec(),
+    _ => sha2::Sha512::digest(raw).to_vec(),
+  });
+  if hashes
+    .iter()
+    .any(|(algorithm, hash)| *algorithm == strongest && *hash == digest)
+  {
+    return true;
+  }
+
+  let inlined = ctx.config.integrity == IntegrityPolicy::Warn;
+  let message = format!(
+    "`{}` doesn't match its integrity metadata{}",
+    path,
+    if inlined {
+      ", it was inlined anyway"
+    } else {
+      "; it was left external"
+    }
+  );
+  ctx.warn(WarningKind::IntegrityMismatch, message);
+  inlined
+}
+
+/// The URL of the source map `url` of the asset at `path` references once inlined, see
+/// `Config::source_maps`. `None` to remove the reference.
+fn source_map_reference(ctx: &mut Context, url: &str, path: &str) -> crate::Result<Option<String>> {
+  match ctx.config.source_maps {
+    SourceMapPolicy::Remove => {
+      log::debug!("[INLINER] removing the source map reference of `{}`", path);
+      Ok(None)
+    }
+    SourceMapPolicy::Keep => Ok(Some(url.to_string())),
+    SourceMapPolicy::Inline if url.starts_with("data:") => Ok(Some(url.to_string())),
+    SourceMapPolicy::Inline => {
+      let map_path = resolve_reference(ctx, path, url);
+      let data_uri = crate::get(ctx, &map_path)?.map(|map| {
+        format!(
+          "data:application/json;charset=utf-8;base64,{}",
+          crate::encode_base64(map.as_bytes())
+        )
+      });
+      if data_uri.is_none() {
+        log::debug!("[INLINER] the source map `{}` could not be inlined", url);
+      }
+      Ok(data_uri)
+    }
+  }
+}
+
+/// Applies `Config::source_maps` to the `sourceMappingURL` comments of the script loaded from
+/// `script_path`.
+pub fn apply_source_map_policy(
+  ctx: &mut Context,
+  script: String,
+  script_path: &str,
+) -> crate::Result<String> {
+  static SOURCE_MAPPING_URL: Lazy<regex::Regex> = Lazy::new(|| {
+    regex::Regex::new(r"(?m)^[ \t]*(?://[#@][ \t]*sourceMappingURL=(\S+)[ \t]*$|/\*[#@][ \t]*sourceMappingURL=(\S+)[ \t]*\*/[ \t]*$)\r?\n?").unwrap()
+  });
+
+  if ctx.config.source_maps == SourceMapPolicy::Keep || !script.contains("sourceMappingURL") {
+    return Ok(script);
+  }
+  let mut rewritten = String::with_capacity(script.len());
+  let mut last = 0;
+  for captures in SOURCE_MAPPING_URL.captures_iter(&script) {
+    let comment = captures.get(0).unwrap();
+    rewritten.push_str(&script[last..comment.start()]);
+    last = comment.end();
+    let url = captures
+      .get(1)
+      .or_else(|| captures.get(2))
+      .unwrap()
+      .as_str();
+    if let Some(url) = source_map_reference(ctx, url, script_path)? {
+      rewritten.push_str(&format!("//# sourceMappingURL={}\n", url));
+    }
+  }
+  rewritten.push_str(&script[last..]);
+  Ok(rewritten)
+}
+
+/// Whether a `<script>` of this `type` holds JS.
+fn is_javascript(script_type: Option<&str>) -> bool {
+  match script_type.map(|script_type| script_type.trim().to_ascii_lowercase()) {
+    None => true,
+    Some(script_type) => matches!(
+      script_type.as_str(),
+      "" | "module" | "text/javascript" | "application/javascript"
+    ),
+  }
+}
+
+/// The ranges of the contents of the string literals of `script`, skipping its comments. Template
+/// literals are included when they have no substitution; regular expressions aren't recognized.
+fn string_literals(script: &str) -> Vec<std::ops::Range<usize>> {
+  let bytes = script.as_bytes();
+  let mut literals = Vec::new();
+  let mut i = 0;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'/' if bytes.get(i + 1) == Some(&b'/') => {
+        i = script[i..].find('\n').map_or(bytes.len(), |end| i + end);
+      }
+      b'/' if bytes.get(i + 1) == Some(&b'*') => {
+        i = script[i + 2..]
+          .find("*/")
+          .map_or(bytes.len(), |end| i + 2 + end + 2);
+      }
+      quote @ (b'"' | b'\'' | b'`') => {
+        let start = i + 1;
+        let mut end = start;
+        while end < bytes.len() && bytes[end] != quote && (quote == b'`' || bytes[end] != b'\n') {
+          end += if bytes[end] == b'\\' { 2 } else { 1 };
+        }
+        let end = end.min(bytes.len());
+        if quote != b'`' || !script[start..end].contains("${") {
+          literals.push(start..end);
+        }
+        i = end + 1;
+      }
+      _ => i += 1,
+    }
+  }
+  literals
+}
+
+/// Replaces the string literals of the inline scripts matching `Config::script_asset_patterns`
+/// with the data URI of the local file they reference.
+pub fn inline_script_assets(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  if ctx.config.script_asset_patterns.is_empty() {
+    return Ok(());
+  }
+  let scripts: Vec<NodeRef> = document
+    .select("script:not([src])")
+    .unwrap()
+    .filter(|script| is_javascript(script.attributes.borrow().get("type")))
+    .map(|script| script.as_node().clone())
+    .collect();
+
+  for node in scripts {
+    let script = node.text_contents();
+    let mut rewritten = String::with_capacity(script.len());
+    let mut last = 0;
+    for literal in string_literals(&script) {
+      let path = &script[literal.clone()];
+      let is_candidate = !path.contains('\\')
+        && ctx
+          .config
+          .script_asset_patterns
+          .iter()
+          .any(|pattern| pattern.is_match(path))
+        && Path::new(path)
+          .components()
+          .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        && url::Url::parse(&ctx.resolve(path)).is_err();
+      if !is_candidate {
+        continue;
+      }
+      let quote = script.as_bytes()[literal.start - 1] as char;
+      let data_uri = match crate::get(ctx, path)? {
+        Some(data_uri) if data_uri.starts_with("data:") && !data_uri.contains(['\\', quote]) => {
+          data_uri
+        }
+        _ => continue,
+      };
+      log::debug!("[INLINER] inlining the script asset `{}`", path);
+      rewritten.push_str(&script[last..literal.start]);
+      rewritten.push_str(&data_uri);
+      last = literal.end;
+    }
+    if last > 0 {
+      rewritten.push_str(&script[last..]);
+      for child in node.children() {
+        child.detach();
+      }
+      node.append(NodeRef::new_text(rewritten));
+    }
+  }
+  Ok(())
+}
+
+/// Embeds the local WebAssembly modules of the `fetch("....wasm")` calls of the inline scripts,
+/// replacing the calls with a promise of a `Response` of the decoded bytes, see
+/// `Config::inline_wasm`.
+pub fn inline_wasm(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  static FETCH_WASM: Lazy<regex::Regex> = Lazy::new(|| {
+    regex::Regex::new(r#"\bfetch\s*\(\s*(["'])([^"'\r\n]+\.wasm(?:[?#][^"'\r\n]*)?)(["'])\s*\)"#)
+      .unwrap()
+  });
+
+  if !ctx.config.inline_wasm {
+    return Ok(());
+  }
+  let scripts: Vec<NodeRef> = document
+    .select("script:not([src])")
+    .unwrap()
+    .filter(|script| is_javascript(script.attributes.borrow().get("type")))
+    .map(|script| script.as_node().clone())
+    .collect();
+
+  for node in scripts {
+    let script = node.text_contents();
+    let mut rewritten = String::with_capacity(script.len());
+    let mut last = 0;
+    for captures in FETCH_WASM.captures_iter(&script) {
+      let specifier = captures.get(2).unwrap();
+      if captures[1] != captures[3]
+        || url::Url::parse(specifier.as_str()).is_ok_and(|url| url.scheme() != "file")
+      {
+        continue;
+      }
+      let wasm = match crate::get(ctx, specifier.as_str())? {
+        Some(wasm) => wasm,
+        None => continue,
+      };
+      let base64 = match wasm
+        .strip_prefix("data:application/wasm;base64,")
+        .and_then(|base64| base64.split('#').next())
+      {
+        Some(base64) => base64,
+        None => continue,
+      };
+      log::debug!(
+        "[INLINER] inlining the WebAssembly module `{}`",
+        specifier.as_str()
+      );
+      let whole = captures.get(0).unwrap();
+      rewritten.push_str(&script[last..whole.start()]);
+      rewritten.push_str(&format!(
+        r#"Promise.resolve(new Response(Uint8Array.from(atob("{}"),function(c){{return c.charCodeAt(0)}}),{{headers:{{"Content-Type":"application/wasm"}}}}))"#,
+        base64
+      ));
+      last = whole.end();
+    }
+    if last > 0 {
+      rewritten.push_str(&script[last..]);
+      for child in node.children() {
+        child.detach();
+      }
+      node.append(NodeRef::new_text(rewritten));
+    }
+  }
+  Ok(())
+}
+
+/// Embeds the local scripts of the `new Worker("...")` and `new SharedWorker("...")` calls of the
+/// inline scripts as Blob URLs, see `Config::inline_workers`.
+pub fn inline_workers(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  static WORKER: Lazy<regex::Regex> = Lazy::new(|| {
+    regex::Regex::new(r#"\bnew\s+(?:Shared)?Worker\s*\(\s*(["'])([^"'\r\n]+)(["'])"#).unwrap()
+  });
+
+  if !ctx.config.inline_workers {
+    return Ok(());
+  }
+  let scripts: Vec<NodeRef> = document
+    .select("script:not([src])")
+    .unwrap()
+    .filter(|script| is_javascript(script.attributes.borrow().get("type")))
+    .map(|script| script.as_node().clone())
+    .collect();
+
+  for node in scripts {
+    let script = node.text_contents();
+    let mut rewritten = String::with_capacity(script.len());
+    let mut last = 0;
+    for captures in WORKER.captures_iter(&script) {
+      let specifier = captures.get(2).unwrap();
+      if captures[1] != captures[3]
+        || url::Url::parse(specifier.as_str()).is_ok_and(|url| url.scheme() != "file")
+      {
+        continue;
+      }
+      let worker = match crate::get(ctx, specifier.as_str())? {
+        Some(worker) => worker,
+        None => continue,
+      };
+      log::debug!("[INLINER] inlining the worker `{}`", specifier.as_str());
+      rewritten.push_str(&script[last..captures.get(1).unwrap().start()]);
+      rewritten.push_str(&format!(
+        r#"URL.createObjectURL(new Blob([{}],{{type:"text/javascript"}}))"#,
+        escape_raw_text(&serde_json::to_string(&worker).unwrap(), "script")
+      ));
+      last = captures.get(3).unwrap().end();
+    }
+    if last > 0 {
+      rewritten.push_str(&script[last..]);
+      for child in node.children() {
+        child.detach();
+      }
+      node.append(NodeRef::new_text(rewritten));
+    }
+  }
+  Ok(())
+}
+
+/// The position after the `)` closing the call whose arguments start at `start`, skipping strings.
+fn call_end(script: &str, start: usize) -> Option<usize> {
+  let bytes = script.as_bytes();
+  let mut depth = 1;
+  let mut i = start;
+  while i < bytes.len() {
+    match bytes[i] {
+      quote @ (b'"' | b'\'' | b'`') => {
+        i += 1;
+        while i < bytes.len() && bytes[i] != quote {
+          if bytes[i] == b'\\' {
+            i += 1;
+          }
+          i += 1;
+        }
+      }
+      b'(' => depth += 1,
+      b')' => {
+        depth -= 1;
+        if depth == 0 {
+          return Some(i + 1);
+        }
+      }
+      _ => {}
+    }
+    i += 1;
+  }
+  None
+}
+
+/// Applies `Config::service_workers` to the `navigator.serviceWorker.register()` calls of the
+/// inline scripts.
+pub fn apply_service_worker_policy(ctx: &mut Context, document: &NodeRef) {
+  static REGISTER: Lazy<regex::Regex> = Lazy::new(|| {
+    regex::Regex::new(r"\bnavigator\s*\.\s*serviceWorker\s*\.\s*register\s*\(").unwrap()
+  });
+
+  let policy = ctx.config.service_workers;
+  if policy == ServiceWorkers::Keep {
+    return;
+  }
+  let scripts: Vec<NodeRef> = document
+    .select("script:not([src])")
+    .unwrap()
+    .filter(|script| is_javascript(script.attributes.borrow().get("type")))
+    .map(|script| script.as_node().clone())
+    .collect();
+
+  for node in scripts {
+    let script = node.text_contents();
+    let mut rewritten = String::with_capacity(script.len());
+    let mut last = 0;
+    for register in REGISTER.find_iter(&script) {
+      if register.start() < last {
+        continue;
+      }
+      let end = match call_end(&script, register.end()) {
+        Some(end) => end,
+        None => continue,
+      };
+      let call = &script[register.start()..end];
+      match policy {
+        ServiceWorkers::Warn => {
+          let message = format!("`{}` registers a service worker", call);
+          ctx.warn(WarningKind::ServiceWorker, message);
+        }
+        ServiceWorkers::Remove => {
+          log::debug!(
+            "[INLINER] removing the service worker registration `{}`",
+            call
+          );
+          rewritten.push_str(&script[last..register.start()]);
+          // a promise that never settles, so the code chained to the registration doesn't run
+          rewritten.push_str("new Promise(function(){})");
+          last = end;
+        }
+        ServiceWorkers::Keep => unreachable!(),
+      }
+    }
+    if last > 0 {
+      rewritten.push_str(&script[last..]);
+      for child in node.children() {
+        child.detach();
+      }
+      node.append(NodeRef::new_text(rewritten));
+    }
+  }
+}
+
+/// Decodes a stylesheet to UTF-8 from its encoding: the one of its byte order mark, else
+/// `charset` (of its `Content-Type`), else the one of its `@charset` rule, else UTF-8.
+/// The `@charset` rule is dropped, as it no longer matches the text.
+pub fn decode_css(raw: &[u8], charset: Option<&str>) -> String {
+  static CHARSET_RULE: Lazy<regex::bytes::Regex> =
+    Lazy::new(|| regex::bytes::Regex::new(r#"^@charset "([^"]*)";"#).unwrap());
+
+  let declared = CHARSET_RULE.captures(raw);
+  let encoding = encoding_rs::Encoding::for_bom(raw)
+    .map(|(encoding, _)| encoding)
+    .or_else(|| charset.and_then(|charset| encoding_rs::Encoding::for_label(charset.as_bytes())))
+    .or_else(|| {
+      declared
+        .as_ref()
+        .and_then(|captures| encoding_rs::Encoding::for_label(&captures[1]))
+    })
+    .unwrap_or(encoding_rs::UTF_8)
+    // a stylesheet can't declare itself as UTF-16 from ASCII compatible bytes
+    .output_encoding();
+  let (css, _, malformed) = encoding.decode(raw);
+  if malformed {
+    log::debug!("[INLINER] the stylesheet isn't valid {}", encoding.name());
+  }
+  match CHARSET_RULE.find(css.as_bytes()) {
+    Some(rule) => css[rule.end()..].to_string(),
+    None => css.into_owned(),
+  }
+}
+
+/// The `selectors` elements of `document` applying to it, leaving out the ones of SVG images and
+/// templates.
+fn document_styles(document: &NodeRef, selectors: &str) -> Vec<NodeDataRef<ElementData>> {
+  document
+    .select(selectors)
+    .unwrap()
+    .filter(|style| {
+      !style.as_node().ancestors().any(|ancestor| {
+        ancestor
+          .as_element()
+          .is_some_and(|element| matches!(&*element.name.local, "svg" | "template"))
+      })
+    })
+    .collect()
+}
+
+/// Replaces the text of a `<style>`.
+fn set_style_text(style: &NodeRef, css: String) {
+  for child in style.children() {
+    child.detach();
+  }
+  style.append(NodeRef::new_text(css));
+}
+
+/// Removes the top-level `@font-face` rules identical to an earlier one applying to the whole
+/// document, e.g. from a font stylesheet imported by several stylesheets, which embed the same
+/// fonts again.
+pub fn dedupe_font_faces(document: &NodeRef) {
+  let mut font_faces = std::collections::HashSet::new();
+  for style in document_styles(document, "style") {
+    let applies_everywhere = {
+      let attributes = style.attributes.borrow();
+      attributes
+        .get("media")
+        .is_none_or(|media| media.trim().is_empty() || media.trim().eq_ignore_ascii_case("all"))
+        && !attributes.contains("disabled")
+        && !attributes.contains("title")
+    };
+    let css = style.text_contents();
+    let mut deduped = String::with_capacity(css.len());
+    let mut removed = 0;
+    for rule in css_rules::parse(&css) {
+      match rule {
+        Rule::Other(text) if is_font_face(text) => {
+          if font_faces.contains(text.trim()) {
+            removed += 1;
+            continue;
+          }
+          if applies_everywhere {
+            font_faces.insert(text.trim().to_string());
+          }
+          deduped.push_str(text);
+        }
+        Rule::Style { text, .. } | Rule::Other(text) => deduped.push_str(text),
+        Rule::Group { .. } => deduped.push_str(&css_rules::filter(&[rule], &mut |_| true)),
+      }
+    }
+    if removed > 0 {
+      log::debug!("[INLINER] removed {} duplicate @font-face rules", removed);
+      if deduped.trim().is_empty() {
+        style.as_node().detach();
+      } else {
+        set_style_text(style.as_node(), deduped);
+      }
+    }
+  }
+}
+
+fn is_font_face(rule: &str) -> bool {
+  rule
+    .get(.."@font-face".len())
+    .is_some_and(|name| name.eq_ignore_ascii_case("@font-face"))
+}
+
+/// Removes the rules of every `<style>` whose selectors match no element of `document`, unless
+/// they match `Config::purge_safelist`. See `Config::purge_css`.
+pub fn purge_unused_css(ctx: &Context, document: &NodeRef) {
+  if !ctx.config.purge_css {
+    return;
+  }
+  for style in document_styles(document, "style") {
+    let css = style.text_contents();
+    let rules = css_rules::parse(&css);
+    let purged = css_rules::filter(&rules, &mut |rule| match rule {
+      Rule::Style { selectors, .. } => {
+        ctx
+          .config
+          .purge_safelist
+          .iter()
+          .any(|pattern| pattern.is_match(selectors))
+          || css_rules::matches(document, selectors)
+      }
+      _ => true,
+    });
+    if purged.len() < css.len() {
+      log::debug!(
+        "[INLINER] purged {} bytes of unused CSS",
+        css.len() - purged.len()
+      );
+      if purged.trim().is_empty() {
+        style.as_node().detach();
+      } else {
+        set_style_text(style.as_node(), purged);
+      }
+    }
+  }
+}
+
+/// Whether the `<style>` can be merged with the other ones: it has no attribute but its `media`
+/// and `type`, and no `@import` nor `@namespace`, which must stay at the start of a stylesheet.
+fn is_mergeable(style: &NodeDataRef<ElementData>) -> bool {
+  &*style.name.local == "style"
+    && style
+      .attributes
+      .borrow()
+      .map
+      .iter()
+      .all(|(name, attribute)| match &*name.local {
+        "media" => true,
+        "type" => attribute.value.is_empty() || attribute.value.eq_ignore_ascii_case("text/css"),
+        _ => false,
+      })
+    && !["@import", "@namespace"]
+      .iter()
+      .any(|rule| style.text_contents().contains(rule))
+}
+
+/// Merges the consecutive `<style>` elements of `document` into the first one of each run, see
+/// `Config::merge_styles`.
+pub fn merge_styles(ctx: &Context, document: &NodeRef) {
+  if !ctx.config.merge_styles {
+    return;
+  }
+  let mut run: Vec<NodeRef> = Vec::new();
+  for sheet in document_styles(document, "style, link[rel~=stylesheet]") {
+    let node = sheet.as_node().clone();
+    // the merged element gets the CSP nonce if the first one of the run was generated
+    let generated = ctx.generated.contains(&node);
+    if run
+      .first()
+      .is_some_and(|first| ctx.generated.contains(first) != generated)
+    {
+      merge_run(std::mem::take(&mut run));
+    }
+    if is_mergeable(&sheet) {
+      run.push(node);
+    } else {
+      merge_run(std::mem::take(&mut run));
+    }
+  }
+  merge_run(run);
+}
+
+fn merge_run(styles: Vec<NodeRef>) {
+  if styles.len() < 2 {
+    return;
+  }
+  let mut merged = String::new();
+  for style in &styles {
+    let css = style.text_contents();
+    let mut css = css.trim_start();
+    // the document's encoding applies to all of them
+    if css.starts_with("@charset") {
+      css = css.find(';').map_or("", |end| &css[end + 1..]);
+    }
+    let media = style
+      .as_element()
+      .unwrap()
+      .attributes
+      .borrow()
+      .get("media")
+      .map(str::trim)
+      .map(str::to_string);
+    match media {
+      Some(media) if !media.is_empty() && !media.eq_ignore_ascii_case("all") => {
+        merged.push_str(&format!("@media {}{{{}}}", media, css));
+      }
+      _ => merged.push_str(css),
+    }
+  }
+  log::debug!("[INLINER] merged {} <style> elements", styles.len());
+  let first = &styles[0];
+  first
+    .as_element()
+    .unwrap()
+    .attributes
+    .borrow_mut()
+    .remove("media");
+  set_style_text(first, merged);
+  for style in &styles[1..] {
+    style.detach();
+  }
+}
+
+/// Splits every `<style>` of `document` into the rules matching its elements, kept in place, and
+/// the other ones, moved to a `<style>` right after it that only applies once the document is
+/// loaded. See `Config::critical_css`.
+pub fn extract_critical_css(ctx: &mut Context, document: &NodeRef) {
+  if !ctx.config.critical_css {
+    return;
+  }
+  // the document is the output of an earlier run
+  if document
+    .select_first(&format!("style[{}]", DEFERRED_MEDIA_ATTRIBUTE))
+    .is_ok()
+  {
+    return;
+  }
+  let mut deferred_styles = false;
+  for style in document_styles(document, "style") {
+    let css = style.text_contents();
+    let rules = css_rules::parse(&css);
+    let mut matched: HashMap<String, bool> = HashMap::new();
+    let mut is_critical = |rule: &Rule<'_>| match rule {
+      Rule::Style { selectors, .. } => *matched
+        .entry(selectors.to_string())
+        .or_insert_with(|| css_rules::matches(document, selectors)),
+      _ => true,
+    };
+    let critical = css_rules::filter(&rules, &mut is_critical);
+    let deferred = css_rules::filter(&rules, &mut |rule| {
+      matches!(rule, Rule::Style { .. }) && !is_critical(rule)
+    });
+    if deferred.is_empty() {
+      continue;
+    }
+
+    let attributes = style.attributes.borrow();
+    let media = attributes.get("media").unwrap_or("all").to_string();
+    let replacement = replacement_element("style", &attributes, &["media"], deferred);
+    drop(attributes);
+    if let Some(element) = replacement.as_element() {
+      let mut attributes = element.attributes.borrow_mut();
+      attributes.insert("media", "not all".into());
+      attributes.insert(DEFERRED_MEDIA_ATTRIBUTE, media);
+    }
+    if ctx.generated.contains(style.as_node()) {
+      ctx.generate(&replacement);
+    }
+    style.as_node().insert_after(replacement);
+    if critical.trim().is_empty() {
+      style.as_node().detach();
+    } else {
+      set_style_text(style.as_node(), critical);
+    }
+    deferred_styles = true;
+  }
+
+  if deferred_styles {
+    let loader = NodeRef::new_element(
+      QualName::new(None, ns!(html), local_name!("script")),
+      std::iter::empty(),
+    );
+    loader.append(NodeRef::new_text(DEFERRED_STYLES_LOADER));
+    ctx.generate(&loader);
+    match document.select_first("body") {
+      Ok(body) => body.as_node().append(loader),
+      Err(()) => document.append(loader),
+    }
+  }
+}
+
+/// Inlines the stylesheet at `css_path`, checked against the `integrity` metadata of the `<link>`
+/// referencing it if any.
+fn inline_css_path(
+  ctx: &mut Context,
   css_path: &str,
-  config: &super::Config,
-  root_path: P,
+  integrity: Option<&str>,
 ) -> crate::Result<Option<String>> {
-  let css = crate::get(&mut cache, css_path, &config, &root_path)?;
-  inline_css(&mut cache, css, css_path, &config, &root_path)
+  let css = crate::get_verified(ctx, css_path, integrity)?;
+  let key = ctx.asset_key(css_path);
+  ctx.import_chain.push(key);
+  let res = inline_stylesheet(ctx, css, css_path);
+  ctx.import_chain.pop();
+  res
 }
 
-fn inline_css<P: AsRef<Path>>(
-  mut cache: &mut HashMap<String, Option<String>>,
+/// Inlines a stylesheet, applying `Config::source_maps` to its `sourceMappingURL` comment.
+fn inline_stylesheet(
+  ctx: &mut Context,
   css: Option<String>,
   css_path: &str,
-  config: &super::Config,
-  root_path: P,
 ) -> crate::Result<Option<String>> {
-  let comment_remover = regex::Regex::new(r#"/\*[^*]*\*+(?:[^/*][^*]*\*+)*/"#).unwrap();
+  static SOURCE_MAPPING_URL: Lazy<regex::Regex> =
+    Lazy::new(|| regex::Regex::new(r"/\*[#@][ \t]*sourceMappingURL=(\S+)[ \t]*\*/").unwrap());
 
-  let import_finder: regex::Regex = regex::Regex::new(r#"(@import)(\s*.*?);"#).unwrap(); // Finds all @import in the css
-  let url_finder = regex::Regex::new(r#"url\s*?\(\s*?["']?([^"')]+?)["']?\s*?\)"#).unwrap(); // Finds all url(path) in the css and makes them relative to the html file
+  // the comments are removed along the way
+  let source_map = match css
+    .as_deref()
+    .and_then(|css| SOURCE_MAPPING_URL.captures_iter(css).last())
+  {
+    Some(captures) => source_map_reference(ctx, &captures[1], css_path)?,
+    None => None,
+  };
+  Ok(match (inline_css(ctx, css, css_path)?, source_map) {
+    (Some(css), Some(url)) => Some(format!("{}\n/*# sourceMappingURL={} */", css, url)),
+    (css, _) => css,
+  })
+}
 
-  let mut is_alright: crate::Result<()> = Ok(());
+fn inline_css(
+  ctx: &mut Context,
+  css: Option<String>,
+  css_path: &str,
+) -> crate::Result<Option<String>> {
+  let font_src_finder = regex::Regex::new(r#"(@font-face\s*\{[^}]*?\bsrc\s*:)([^;}]*)"#).unwrap(); // Finds the src list of every @font-face
 
-  let css_data = css.map(|resolved_css| {
-    let resolved_css = comment_remover.replace_all(&resolved_css, |_: &Captures| "".to_owned());
-    let resolved_css = import_finder.replace_all(&resolved_css, |caps: &Captures| {
-      let match_url = caps[2].trim().to_string();
-      let match_url = if match_url.starts_with("url") {
-        match_url.replace("url", "")
-      } else {
-        match_url
-      }
-      .replace("'", "")
-      .replace("\"", "")
-      .replace("}", "")
-      .replace("(", "")
-      .replace(")", "")
-      .replace(";", "");
-      let mut match_split = match_url.split(' ');
-      let css_url = match_split.next().unwrap();
-      let url_path = if let Ok(url) = url::Url::parse(&css_path) {
-        url.join(&css_url).unwrap().to_string()
-      } else {
-        root_path
-          .as_ref()
-          .join(&css_url)
-          .into_os_string()
-          .into_string()
-          .unwrap()
-      };
-      match inline_css_path(&mut cache, &url_path, &config, root_path.as_ref()) {
-        Ok(out) => {
-          let inlined_css = out.map(compress_css).unwrap_or_else(|| "".to_owned());
-          if match_split.next().is_some() {
-            format!(
-              "@media {}{{{}}}",
-              match_url.replace(&format!("{} ", css_url), ""),
-              inlined_css
-            )
-          } else {
-            inlined_css
+  let css = match css {
+    Some(css) => css,
+    None => return Ok(None),
+  };
+  let css = if ctx.config.target.needs_font_fallbacks() {
+    css
+  } else {
+    font_src_finder
+      .replace_all(&css, |caps: &Captures| {
+        format!("{}{}", &caps[1], drop_font_fallbacks(&caps[2]))
+      })
+      .to_string()
+  };
+  let flattens_imports = ctx.import_depth == 0 && css.to_ascii_lowercase().contains("@import");
+  let css = rewrite_css(ctx, &css, css_path)?;
+  let preamble = if flattens_imports {
+    let preamble = layer_preamble(&css).unwrap_or_default();
+    // browsers ignore an `@import` following other rules
+    let hoisted = std::mem::take(&mut ctx.hoisted_imports).concat();
+    Some(format!("{}{}", preamble, hoisted)).filter(|preamble| !preamble.is_empty())
+  } else {
+    None
+  };
+  let css = match preamble {
+    // the preamble goes first, only after `@charset`
+    Some(preamble) => match css
+      .trim_start()
+      .strip_prefix("@charset")
+      .and_then(|rest| rest.split_once(';'))
+    {
+      Some((charset, rest)) => format!("@charset{};{}{}", charset, preamble, rest),
+      None => format!("{}{}", preamble, css),
+    },
+    None => css,
+  };
+  #[cfg(feature = "woff2")]
+  let css = if ctx.config.convert_fonts_to_woff2 {
+    let format_finder =
+      regex::Regex::new(r#"(url\('data:font/woff2;[^']*'\)\s*)format\([^)]*\)"#).unwrap(); // Finds the format hint of every inlined WOFF2 font
+    format_finder
+      .replace_all(&css, r#"${1}format("woff2")"#)
+      .to_string()
+  } else {
+    css
+  };
+  Ok(Some(compress_css(ctx.config.transform_level, css)))
+}
+
+/// The `@layer` statement declaring the cascade layers of a stylesheet whose imports were
+/// flattened, in the order they first appear, so they keep the order the imports gave them.
+/// The layers following an anonymous one are left out, since declaring them first would move
+/// them before it. `None` if no layer is declared, or if an `@import` remains, whose layers
+/// would come later.
+fn layer_preamble(css: &str) -> Option<String> {
+  let bytes = css.as_bytes();
+  let mut names: Vec<String> = Vec::new();
+  // the qualified name of the layer of every open block, `None` for the other blocks
+  let mut blocks: Vec<Option<String>> = Vec::new();
+  let mut quote = None;
+  let mut i = 0;
+  while i < bytes.len() {
+    let c = bytes[i];
+    i += 1;
+    match (quote, c) {
+      (Some(_), b'\\') => i += 1,
+      (Some(q), c) if c == q => quote = None,
+      (Some(_), _) => {}
+      (None, b'"') | (None, b'\'') => quote = Some(c),
+      (None, b'{') => blocks.push(None),
+      (None, b'}') => {
+        blocks.pop();
+      }
+      (None, b'@') => {
+        let rest = &css[i..];
+        let keyword = &rest[..rest
+          .find(|c: char| !(c.is_alphanumeric() || c == '-'))
+          .unwrap_or(rest.len())];
+        if keyword.eq_ignore_ascii_case("import") {
+          return None;
+        }
+        if !keyword.eq_ignore_ascii_case("layer") {
+          continue;
+        }
+        let prelude = &rest[keyword.len()..];
+        let end = prelude.find(['{', ';']).unwrap_or(prelude.len());
+        let parent = blocks.iter().rev().find_map(|layer| layer.clone());
+        let qualify = |name: &str| match &parent {
+          Some(parent) => format!("{}.{}", parent, name),
+          None => name.to_string(),
+        };
+        let layers: Vec<String> = prelude[..end]
+          .split(',')
+          .map(str::trim)
+          .filter(|name| !name.is_empty())
+          .map(qualify)
+          .collect();
+        if layers.is_empty() {
+          break;
+        }
+        for layer in &layers {
+          if !names.contains(layer) {
+            names.push(layer.clone());
           }
         }
-        Err(e) => {
-          is_alright = Err(e);
-          "".to_owned()
+        i += keyword.len() + end;
+        if prelude[end..].starts_with('{') {
+          blocks.push(layers.into_iter().next());
+          i += 1;
         }
       }
-    });
+      _ => {}
+    }
+  }
+  if names.is_empty() {
+    None
+  } else {
+    Some(format!("@layer {};", names.join(",")))
+  }
+}
+
+/// Resolves a reference of the stylesheet at `css_path` against the stylesheet's own location.
+/// `css_path` is the document base for inline CSS, which resolves from the root itself.
+/// A reference that can't be resolved (a malformed URL, a non UTF-8 path) is returned unchanged.
+pub fn resolve_reference(ctx: &Context, css_path: &str, reference: &str) -> String {
+  let resolved = if let Ok(url) = url::Url::parse(css_path) {
+    url.join(reference).map(String::from).ok()
+  } else if let Ok(url) = url::Url::parse(reference) {
+    Some(url.to_string())
+  } else {
+    let css_path = Path::new(css_path);
+    let directory = if css_path == ctx.root_path {
+      ctx.root_path.clone()
+    } else {
+      let css_path = ctx.root_path.join(css_path);
+      css_path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| ctx.root_path.clone())
+    };
+    directory
+      .join(reference)
+      .into_os_string()
+      .into_string()
+      .ok()
+  };
+  resolved.unwrap_or_else(|| {
+    log::debug!(
+      "[INLINER] couldn't resolve `{}` against `{}`, leaving it unchanged",
+      reference,
+      css_path
+    );
+    reference.to_string()
+  })
+}
 
-    let resolved_css = url_finder.replace_all(&resolved_css, |caps: &Captures| {
-      if caps[1].trim().starts_with("data:") {
-        return caps[0].to_owned();
+/// The `layer()`, `supports()` and media conditions following the URL of an `@import`.
+#[derive(Debug, Default, PartialEq)]
+struct ImportConditions {
+  /// The cascade layer the stylesheet is imported in, empty for an anonymous `layer`.
+  layer: Option<String>,
+  /// The `supports()` condition, a declaration or a supports condition.
+  supports: Option<String>,
+  media: String,
+}
+
+impl ImportConditions {
+  /// The conditions of an `@import` with these `inner` conditions in a stylesheet imported with
+  /// these ones, `None` if they can't be expressed as a single `@import`.
+  fn nest(&self, inner: &ImportConditions) -> Option<ImportConditions> {
+    let layer = match (&self.layer, &inner.layer) {
+      // an anonymous layer can't be referenced by a layer name
+      (Some(outer), _) if outer.is_empty() => return None,
+      (Some(_), Some(inner)) if inner.is_empty() => return None,
+      (Some(outer), Some(inner)) => Some(format!("{}.{}", outer, inner)),
+      (outer, inner) => outer.clone().or_else(|| inner.clone()),
+    };
+    let supports = match (&self.supports, &inner.supports) {
+      (Some(outer), Some(inner)) => Some(format!(
+        "{} and {}",
+        supports_in_parens(outer),
+        supports_in_parens(inner)
+      )),
+      (outer, inner) => outer.clone().or_else(|| inner.clone()),
+    };
+    let media = if self.media.is_empty() || inner.media.is_empty() {
+      format!("{}{}", self.media, inner.media)
+    } else {
+      let mut queries = Vec::new();
+      for outer in self.media.split(',') {
+        for inner in inner.media.split(',') {
+          queries.push(combine_media_queries(outer.trim(), inner.trim())?);
+        }
       }
-      let url_path = if let Ok(url) = url::Url::parse(&css_path) {
-        url.join(&caps[1]).unwrap().to_string()
-      } else if let Ok(url) = url::Url::parse(&caps[1]) {
-        url.to_string()
+      queries.join(", ")
+    };
+    Some(ImportConditions {
+      layer,
+      supports,
+      media,
+    })
+  }
+
+  /// The conditions as they follow the URL of an `@import`, with a leading space.
+  fn to_conditions(&self) -> String {
+    let mut conditions = String::new();
+    match &self.layer {
+      Some(layer) if layer.is_empty() => conditions.push_str(" layer"),
+      Some(layer) => conditions.push_str(&format!(" layer({})", layer)),
+      None => {}
+    }
+    if let Some(supports) = &self.supports {
+      conditions.push_str(&format!(" supports({})", supports));
+    }
+    if !self.media.is_empty() {
+      conditions.push_str(&format!(" {}", self.media));
+    }
+    conditions
+  }
+
+  /// Wraps the imported `css` in the `@layer`, `@supports` and `@media` blocks it applies to.
+  fn wrap(&self, css: String) -> String {
+    let css = if self.media.is_empty() {
+      css
+    } else {
+      format!("@media {}{{{}}}", self.media, css)
+    };
+    let css = match &self.supports {
+      // a bare declaration needs its parentheses back
+      Some(supports) if is_declaration(supports) => format!("@supports ({}){{{}}}", supports, css),
+      Some(supports) => format!("@supports {}{{{}}}", supports, css),
+      None => css,
+    };
+    match &self.layer {
+      Some(layer) if layer.is_empty() => format!("@layer{{{}}}", css),
+      Some(layer) => format!("@layer {}{{{}}}", layer, css),
+      None => css,
+    }
+  }
+}
+
+/// A `supports()` condition that can be combined with another one.
+fn supports_in_parens(condition: &str) -> String {
+  format!("({})", condition)
+}
+
+/// The media query matching both `outer` and `inner`, `None` if it can't be written as one, e.g.
+/// for two media types or a negated query.
+fn combine_media_queries(outer: &str, inner: &str) -> Option<String> {
+  let has_type = |query: &str| !query.starts_with('(');
+  let lowercase = format!("{} {}", outer, inner).to_ascii_lowercase();
+  if lowercase.starts_with("not ") || lowercase.contains(" or ") {
+    return None;
+  }
+  match (has_type(outer), has_type(inner)) {
+    (true, true) => None,
+    (false, true) if inner.to_ascii_lowercase().starts_with("not ") => None,
+    (false, true) => Some(format!("{} and {}", inner, outer)),
+    _ => Some(format!("{} and {}", outer, inner)),
+  }
+}
+
+/// Whether a `supports()` condition is a declaration (`display: grid`).
+fn is_declaration(condition: &str) -> bool {
+  match condition.split_once(':') {
+    Some((property, _)) => {
+      let property = property.trim();
+      !property.is_empty()
+        && property
+          .chars()
+          .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    }
+    None => false,
+  }
+}
+
+/// Splits the `conditions` of an `@import` following its URL.
+#[cfg(feature = "css-parser")]
+fn import_conditions(conditions: &str) -> ImportConditions {
+  use cssparser::Token;
+
+  let mut input = cssparser::ParserInput::new(conditions);
+  let mut parser = cssparser::Parser::new(&mut input);
+  let mut result = ImportConditions::default();
+  loop {
+    let start = parser.position();
+    let nested = |parser: &mut cssparser::Parser<'_, '_>| {
+      let block_start = parser.position();
+      parser
+        .parse_nested_block(|parser| {
+          while parser.next().is_ok() {}
+          Ok::<_, cssparser::ParseError<'_, ()>>(parser.slice_from(block_start).to_string())
+        })
+        .ok()
+    };
+    match parser.next().cloned() {
+      Ok(Token::Ident(name)) if result.layer.is_none() && name.eq_ignore_ascii_case("layer") => {
+        result.layer = Some(String::new());
+      }
+      Ok(Token::Function(name)) if result.layer.is_none() && name.eq_ignore_ascii_case("layer") => {
+        result.layer = nested(&mut parser).map(|layer| layer.trim().to_string());
+      }
+      Ok(Token::Function(name))
+        if result.supports.is_none() && name.eq_ignore_ascii_case("supports") =>
+      {
+        result.supports = nested(&mut parser).map(|supports| supports.trim().to_string());
+      }
+      // the media queries run up to the end
+      Ok(_) => {
+        while parser.next().is_ok() {}
+        result.media = parser.slice_from(start).trim().to_string();
+        break;
+      }
+      Err(_) => break,
+    }
+  }
+  result
+}
+
+/// Splits the `conditions` of an `@import` following its URL.
+#[cfg(not(feature = "css-parser"))]
+fn import_conditions(conditions: &str) -> ImportConditions {
+  let layer_finder = regex::Regex::new(r#"^(?i)layer(?:\(([^)]*)\))?"#).unwrap(); // Finds the leading layer of the conditions
+  let supports_finder =
+    regex::Regex::new(r#"^(?i)supports\(((?:[^()]|\((?:[^()]|\([^()]*\))*\))*)\)"#).unwrap(); // Finds the leading supports() of the conditions, up to two levels of parentheses deep
+
+  let mut result = ImportConditions::default();
+  let mut conditions = conditions.trim();
+  if let Some(caps) = layer_finder.captures(conditions) {
+    result.layer = Some(
+      caps
+        .get(1)
+        .map_or("", |layer| layer.as_str())
+        .trim()
+        .to_string(),
+    );
+    conditions = conditions[caps[0].len()..].trim_start();
+  }
+  if let Some(caps) = supports_finder.captures(conditions) {
+    result.supports = Some(caps[1].trim().to_string());
+    conditions = conditions[caps[0].len()..].trim_start();
+  }
+  result.media = conditions.to_string();
+  result
+}
+
+/// The replacement of an `@import` of `url` applying to the `conditions` (`layer()`, `supports()`
+/// and media queries) following it, if any.
+fn inline_import(
+  ctx: &mut Context,
+  css_path: &str,
+  url: &str,
+  conditions: &str,
+) -> crate::Result<String> {
+  let url_path = resolve_reference(ctx, css_path, url);
+  // browsers ignore an import cycle, so does the output
+  if ctx.import_chain.contains(&ctx.asset_key(&url_path)) {
+    let message = format!(
+      "`{}` is already being imported and the circular import will be dropped",
+      url_path
+    );
+    ctx.warn(WarningKind::CircularImport, message);
+    return Ok(String::new());
+  }
+  if ctx.import_depth >= ctx.config.max_import_depth {
+    let message = format!(
+      "`{}` exceeds the max import depth of {} and will not be inlined",
+      url_path, ctx.config.max_import_depth
+    );
+    ctx.warn(WarningKind::ImportDepthExceeded, message);
+    // the import goes first in the flattened stylesheet, out of the blocks of the outer imports
+    let nested = ctx
+      .import_conditions
+      .iter()
+      .rev()
+      .try_fold(import_conditions(conditions), |nested, outer| {
+        import_conditions(outer).nest(&nested)
+      });
+    let reference = document_reference(ctx, &url_path);
+    return Ok(match nested {
+      Some(nested) => {
+        let import = format!("@import \"{}\"{};", reference, nested.to_conditions());
+        ctx.hoisted_imports.push(import);
+        String::new()
+      }
+      None => {
+        log::debug!(
+          "[INLINER] the conditions of `{}` can't be combined with the ones of its importers",
+          url_path
+        );
+        let conditions = if conditions.is_empty() {
+          String::new()
+        } else {
+          format!(" {}", conditions)
+        };
+        format!("@import \"{}\"{};", reference, conditions)
+      }
+    });
+  }
+  ctx.import_depth += 1;
+  ctx.import_conditions.push(conditions.to_string());
+  let inlined = inline_css_path(ctx, &url_path, None);
+  ctx.import_conditions.pop();
+  ctx.import_depth -= 1;
+  let inlined_css = inlined?
+    .map(|css| compress_css(ctx.config.transform_level, css))
+    .unwrap_or_default();
+  Ok(import_conditions(conditions).wrap(inlined_css))
+}
+
+/// The replacement of a `url()` referencing `url`, `None` to leave it as is.
+fn inline_url(ctx: &mut Context, css_path: &str, url: &str) -> crate::Result<Option<String>> {
+  // same-document references (`url(#gradient)`) are left for SVG to resolve
+  if url.trim().starts_with("data:") || url.trim().starts_with('#') {
+    return Ok(None);
+  }
+  let url_path = resolve_reference(ctx, css_path, url);
+  Ok(Some(match crate::get(ctx, &url_path)? {
+    Some(resolved) => format!(
+      "url('{}')",
+      if url_path.ends_with(".css") {
+        compress_css(ctx.config.transform_level, &resolved)
       } else {
-        root_path
-          .as_ref()
-          .to_path_buf()
-          .join(&caps[1])
-          .into_os_string()
-          .into_string()
-          .unwrap()
+        resolved
+      }
+    ),
+    None => format!(
+      "url('{}')",
+      oversized_reference(ctx, &url_path)?.unwrap_or_else(|| document_reference(ctx, &url_path))
+    ),
+  }))
+}
+
+/// The reference to `url_path`, as returned by `resolve_reference`, from the document: the
+/// references left external in an inlined stylesheet are resolved against the document instead.
+fn document_reference(ctx: &Context, url_path: &str) -> String {
+  let path = normalize(Path::new(url_path));
+  let root = normalize(&ctx.root_path);
+  if url::Url::parse(url_path).is_ok() || path.is_absolute() != root.is_absolute() {
+    return url_path.to_string();
+  }
+  let common = path
+    .components()
+    .zip(root.components())
+    .take_while(|(component, root)| component == root)
+    .count();
+  std::iter::repeat_n("..".into(), root.components().count() - common)
+    .chain(
+      path
+        .components()
+        .skip(common)
+        .map(|component| component.as_os_str().to_string_lossy()),
+    )
+    .collect::<Vec<_>>()
+    .join("/")
+}
+
+/// The reference to `url_path` replacing the original one when it's left external for being
+/// over `Config::max_inline_size`, see `Config::oversized_urls`.
+fn oversized_reference(ctx: &Context, url_path: &str) -> crate::Result<Option<String>> {
+  if ctx.config.oversized_urls == OversizedUrls::Keep
+    || ctx.exclude.iter().any(|pattern| pattern.is_match(url_path))
+  {
+    return Ok(None);
+  }
+  if let Ok(url) = url::Url::parse(url_path) {
+    return Ok(Some(url.to_string()).filter(|_| url.scheme() != "data"));
+  }
+  let file_path = crate::local_path(&ctx.root_path, url_path);
+  let oversized = std::fs::metadata(&file_path)
+    .is_ok_and(|metadata| metadata.is_file() && metadata.len() > ctx.config.max_inline_size as u64);
+  if !oversized {
+    return Ok(None);
+  }
+  let relative = match normalize(&file_path).strip_prefix(normalize(&ctx.root_path)) {
+    Ok(relative) => relative.to_path_buf(),
+    // outside of the document's directory
+    Err(_) => file_path.file_name().map(PathBuf::from).unwrap_or_default(),
+  };
+  let reference = relative
+    .components()
+    .map(|component| component.as_os_str().to_string_lossy())
+    .collect::<Vec<_>>()
+    .join("/");
+  Ok(Some(match &ctx.config.oversized_urls {
+    OversizedUrls::Keep => unreachable!(),
+    OversizedUrls::FileUrl => match url::Url::from_file_path(file_path.canonicalize()?) {
+      Ok(url) => url.to_string(),
+      Err(()) => return Ok(None),
+    },
+    OversizedUrls::Prefix(prefix) => format!("{}{}", prefix, reference),
+    OversizedUrls::CopyTo(_) if ctx.dry_run => reference,
+    OversizedUrls::CopyTo(directory) => {
+      let destination = directory.join(&relative);
+      if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)?;
+      }
+      std::fs::copy(&file_path, destination)?;
+      log::debug!("[INLINER] copied `{}` to {:?}", url_path, directory);
+      reference
+    }
+  }))
+}
+
+/// `path` without its `.` and `..` components.
+fn normalize(path: &Path) -> PathBuf {
+  let mut normalized = PathBuf::new();
+  for component in path.components() {
+    match component {
+      Component::CurDir => {}
+      Component::ParentDir => {
+        normalized.pop();
+      }
+      component => normalized.push(component),
+    }
+  }
+  normalized
+}
+
+/// Removes the comments of `css` and inlines its `@import`s and `url()`s, walking its tokens.
+#[cfg(feature = "css-parser")]
+pub fn rewrite_css(ctx: &mut Context, css: &str, css_path: &str) -> crate::Result<String> {
+  let mut input = cssparser::ParserInput::new(css);
+  let mut parser = cssparser::Parser::new(&mut input);
+  let mut out = String::with_capacity(css.len());
+  rewrite_tokens(ctx, &mut parser, css_path, Scope::TopLevel, &mut out)?;
+  Ok(out)
+}
+
+/// Where the tokens being rewritten are.
+#[cfg(feature = "css-parser")]
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Scope {
+  /// The stylesheet itself, where `@import` rules are.
+  TopLevel,
+  /// A block or function.
+  Nested,
+  /// The arguments of an `image-set()`, whose strings are image URLs.
+  ImageSet,
+}
+
+#[cfg(feature = "css-parser")]
+fn rewrite_tokens(
+  ctx: &mut Context,
+  parser: &mut cssparser::Parser<'_, '_>,
+  css_path: &str,
+  scope: Scope,
+  out: &mut String,
+) -> crate::Result<()> {
+  use cssparser::Token;
+
+  loop {
+    let start = parser.position();
+    let token = match parser.next_including_whitespace_and_comments() {
+      Ok(token) => token.clone(),
+      Err(_) => return Ok(()),
+    };
+    match token {
+      Token::Comment(_) => {}
+      Token::UnquotedUrl(url) => match inline_url(ctx, css_path, &url)? {
+        Some(replacement) => out.push_str(&replacement),
+        None => out.push_str(parser.slice_from(start)),
+      },
+      Token::Function(name) if name.eq_ignore_ascii_case("url") => {
+        let url = parser.parse_nested_block(|parser| {
+          let url = parser.expect_string()?.to_string();
+          parser.expect_exhausted()?;
+          Ok::<_, cssparser::ParseError<'_, ()>>(url)
+        });
+        match url {
+          Ok(url) => match inline_url(ctx, css_path, &url)? {
+            Some(replacement) => out.push_str(&replacement),
+            None => out.push_str(parser.slice_from(start)),
+          },
+          Err(_) => out.push_str(parser.slice_from(start)),
+        }
+      }
+      Token::QuotedString(url) if scope == Scope::ImageSet => {
+        match inline_url(ctx, css_path, &url)? {
+          Some(replacement) => out.push_str(&replacement),
+          None => out.push_str(parser.slice_from(start)),
+        }
+      }
+      Token::AtKeyword(name) if scope == Scope::TopLevel && name.eq_ignore_ascii_case("import") => {
+        rewrite_import(ctx, parser, css_path, start, out)?;
+      }
+      Token::Function(ref name)
+        if name.eq_ignore_ascii_case("image-set")
+          || name.eq_ignore_ascii_case("-webkit-image-set") =>
+      {
+        rewrite_block(ctx, parser, css_path, Scope::ImageSet, start, out)?;
+      }
+      Token::Function(_)
+      | Token::ParenthesisBlock
+      | Token::SquareBracketBlock
+      | Token::CurlyBracketBlock => {
+        rewrite_block(ctx, parser, css_path, Scope::Nested, start, out)?;
+      }
+      _ => out.push_str(parser.slice_from(start)),
+    }
+  }
+}
+
+/// Rewrites the block (or function) whose opening token starts at `start`.
+#[cfg(feature = "css-parser")]
+fn rewrite_block(
+  ctx: &mut Context,
+  parser: &mut cssparser::Parser<'_, '_>,
+  css_path: &str,
+  scope: Scope,
+  start: cssparser::SourcePosition,
+  out: &mut String,
+) -> crate::Result<()> {
+  out.push_str(parser.slice_from(start));
+  let mut result = Ok(());
+  let mut end = parser.position();
+  let _ = parser.parse_nested_block(|parser| {
+    result = rewrite_tokens(ctx, parser, css_path, scope, out);
+    end = parser.position();
+    Ok::<_, cssparser::ParseError<'_, ()>>(())
+  });
+  result?;
+  // the closing token, if any
+  out.push_str(parser.slice(end..parser.position()));
+  Ok(())
+}
+
+/// Inlines the `@import` whose keyword starts at `start`.
+#[cfg(feature = "css-parser")]
+fn rewrite_import(
+  ctx: &mut Context,
+  parser: &mut cssparser::Parser<'_, '_>,
+  css_path: &str,
+  start: cssparser::SourcePosition,
+  out: &mut String,
+) -> crate::Result<()> {
+  use cssparser::Token;
+
+  let url = match parser.next().cloned() {
+    Ok(Token::QuotedString(url)) | Ok(Token::UnquotedUrl(url)) => Some(url.to_string()),
+    Ok(Token::Function(name)) if name.eq_ignore_ascii_case("url") => parser
+      .parse_nested_block(|parser| {
+        let url = parser.expect_string()?.to_string();
+        Ok::<_, cssparser::ParseError<'_, ()>>(url)
+      })
+      .ok(),
+    _ => None,
+  };
+  // the conditions run up to the semicolon
+  let conditions_start = parser.position();
+  let mut conditions_end = conditions_start;
+  loop {
+    match parser.next() {
+      Ok(Token::Semicolon) | Err(_) => break,
+      Ok(Token::Function(_))
+      | Ok(Token::ParenthesisBlock)
+      | Ok(Token::SquareBracketBlock)
+      | Ok(Token::CurlyBracketBlock) => {
+        // consumes the block, so that it ends the conditions
+        let _ = parser.parse_nested_block(|_| Ok::<_, cssparser::ParseError<'_, ()>>(()));
+      }
+      Ok(_) => {}
+    }
+    conditions_end = parser.position();
+  }
+  match url {
+    Some(url) => {
+      let conditions = parser
+        .slice(conditions_start..conditions_end)
+        .trim()
+        .to_string();
+      out.push_str(&inline_import(ctx, css_path, &url, &conditions)?);
+    }
+    None => out.push_str(parser.slice_from(start)),
+  }
+  Ok(())
+}
+
+/// Removes the comments of `css` and inlines its `@import`s and `url()`s with regexes.
+#[cfg(not(feature = "css-parser"))]
+pub fn rewrite_css(ctx: &mut Context, css: &str, css_path: &str) -> crate::Result<String> {
+  let comment_remover = regex::Regex::new(r#"/\*[^*]*\*+(?:[^/*][^*]*\*+)*/"#).unwrap();
+  let import_finder =
+    regex::Regex::new(r#"@import\s*(?:url\(\s*)?["']?([^"')\s;]+)["']?\s*\)?\s*([^;]*);"#).unwrap(); // Finds all @import in the css, with their url and conditions
+  let url_finder = regex::Regex::new(r#"url\s*?\(\s*?["']?([^"')]+?)["']?\s*?\)"#).unwrap(); // Finds all url(path) in the css and makes them relative to the html file
+  let image_set_finder =
+    regex::Regex::new(r#"(?i)((?:-webkit-)?image-set\()((?:[^()]|\([^()]*\))*)\)"#).unwrap(); // Finds the arguments of every image-set()
+  let image_set_string_finder = regex::Regex::new(r#"(^|[,\s])(["'])([^"']*)["']"#).unwrap(); // Finds the bare URL strings of image-set() arguments
+
+  let mut is_alright: crate::Result<()> = Ok(());
+
+  let css = comment_remover.replace_all(css, |_: &Captures| "".to_owned());
+  // the imported stylesheets are already rewritten: they are put back after the `url()`s
+  let mut imports = Vec::new();
+  let css = import_finder.replace_all(&css, |caps: &Captures| {
+    imports.push(
+      inline_import(ctx, css_path, &caps[1], caps[2].trim()).unwrap_or_else(|e| {
+        is_alright = Err(e);
+        "".to_owned()
+      }),
+    );
+    format!("\u{0}{}\u{0}", imports.len() - 1)
+  });
+
+  let css = image_set_finder.replace_all(&css, |caps: &Captures| {
+    let images = image_set_string_finder.replace_all(&caps[2], |image: &Captures| {
+      let replacement = match inline_url(ctx, css_path, &image[3]) {
+        Ok(replacement) => replacement,
+        Err(e) => {
+          is_alright = Err(e);
+          None
+        }
       };
-      if let Ok(Some(resolved)) = crate::get(&mut cache, &url_path, &config, &root_path) {
-        format!(
-          "url('{}')",
-          if url_path.ends_with(".css") {
-            compress_css(&resolved)
-          } else {
-            resolved
-          }
-        )
-      } else {
+      format!(
+        "{}{}",
+        &image[1],
+        replacement.unwrap_or_else(|| image[0][image[1].len()..].to_owned())
+      )
+    });
+    format!("{}{})", &caps[1], images)
+  });
+
+  let css = url_finder.replace_all(&css, |caps: &Captures| {
+    match inline_url(ctx, css_path, &caps[1]) {
+      Ok(Some(replacement)) => replacement,
+      Ok(None) => caps[0].to_owned(),
+      Err(e) => {
+        is_alright = Err(e);
         format!("url('{}')", &caps[1])
       }
-    });
-    compress_css(resolved_css)
+    }
   });
+  let import_placeholder = regex::Regex::new("\u{0}([0-9]+)\u{0}").unwrap();
+  let css = import_placeholder.replace_all(&css, |caps: &Captures| {
+    imports[caps[1].parse::<usize>().unwrap()].clone()
+  });
+
+  is_alright.map(|_| css.to_string())
+}
+
+/// Removes the non-woff2 entries of a `@font-face` `src` list that has a woff2 source,
+/// keeping the `local()` ones.
+fn drop_font_fallbacks(sources: &str) -> String {
+  let mut entries = vec![];
+  let (mut depth, mut quote, mut start) = (0, None, 0);
+  for (i, c) in sources.char_indices() {
+    match c {
+      '"' | '\'' if quote == Some(c) => quote = None,
+      '"' | '\'' if quote.is_none() => quote = Some(c),
+      '(' if quote.is_none() => depth += 1,
+      ')' if quote.is_none() => depth -= 1,
+      ',' if quote.is_none() && depth == 0 => {
+        entries.push(&sources[start..i]);
+        start = i + 1;
+      }
+      _ => {}
+    }
+  }
+  entries.push(&sources[start..]);
+
+  let is_woff2 = |entry: &&str| entry.to_lowercase().contains("woff2");
+  if !entries.iter().any(is_woff2) {
+    return sources.to_string();
+  }
+  entries
+    .into_iter()
+    .filter(|entry| entry.trim_start().starts_with("local(") || is_woff2(entry))
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+/// Minifies a script loaded from a file with the `js-minify` feature, unless `level` is
+/// `TransformLevel::None`.
+pub fn compress_js(level: TransformLevel, script: String) -> String {
+  #[cfg(feature = "js-minify")]
+  if level != TransformLevel::None {
+    return crate::js_minify::minify(&script);
+  }
+  #[cfg(not(feature = "js-minify"))]
+  let _ = level;
+  script
+}
 
-  is_alright.map(|_| css_data)
-}
-
-fn compress_css<S: Into<String>>(css: S) -> String {
-  let mut css = css.into();
-  let replaces = &[
-    (regex::Regex::new(r"(\s+)").unwrap(), " "),
-    (regex::Regex::new(r":(\s+)").unwrap(), ":"),
-    (regex::Regex::new(r"/\*.*?\*").unwrap(), ""),
-    (regex::Regex::new(r"(\} )").unwrap(), "}"),
-    (regex::Regex::new(r"( \{)").unwrap(), "{"),
-    (regex::Regex::new(r"(; )").unwrap(), ";"),
-    (regex::Regex::new(r"(\n+)").unwrap(), ""),
-  ];
-  for (regex, replace) in replaces {
-    css = regex
-      .replace_all(&css, replace.to_string().as_str())
-      .to_string();
-  }
-  css
+/// Compresses `css` unless `level` is `TransformLevel::None`.
+fn compress_css<S: Into<String>>(level: TransformLevel, css: S) -> String {
+  let css = css.into();
+  if level == TransformLevel::None {
+    return css;
+  }
+  #[cfg(feature = "css-minify")]
+  if let Some(minified) = minify_css(&css) {
+    return minified;
+  }
+  collapse_whitespace(&css)
+}
+
+/// Collapses the runs of whitespace of `css` to a single space, leaving the strings, comments,
+/// `url()` bodies and attribute selectors as they are.
+pub fn collapse_whitespace(css: &str) -> String {
+  let bytes = css.as_bytes();
+  let mut compressed = String::with_capacity(css.len());
+  let mut start = 0;
+  let mut i = 0;
+  while i < bytes.len() {
+    let end = match bytes[i] {
+      b'"' | b'\'' => css_rules::skip_string_or_comment(bytes, i),
+      b'/' if bytes.get(i + 1) == Some(&b'*') => css_rules::skip_string_or_comment(bytes, i),
+      // an escaped character, which may be a space
+      b'\\' => (i + 2).min(bytes.len()),
+      b'[' => closing(bytes, i + 1, b']'),
+      b'(' if is_url_function(&bytes[..i]) => closing(bytes, i + 1, b')'),
+      c if c.is_ascii_whitespace() => {
+        compressed.push_str(&css[start..i]);
+        compressed.push(' ');
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+          i += 1;
+        }
+        start = i;
+        continue;
+      }
+      _ => i + 1,
+    };
+    // never split a multi-byte character
+    i = (end..=bytes.len())
+      .find(|end| css.is_char_boundary(*end))
+      .unwrap_or(bytes.len());
+  }
+  compressed.push_str(&css[start..]);
+  compressed
+}
+
+/// The position after the `delimiter` closing the block opened before `start`, skipping strings.
+fn closing(bytes: &[u8], start: usize, delimiter: u8) -> usize {
+  let mut i = start;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'"' | b'\'' => {
+        i = css_rules::skip_string_or_comment(bytes, i);
+        continue;
+      }
+      b'\\' => i += 1,
+      c if c == delimiter => return i + 1,
+      _ => {}
+    }
+    i += 1;
+  }
+  bytes.len()
+}
+
+/// Whether `prefix` ends with the name of the `url()` function.
+fn is_url_function(prefix: &[u8]) -> bool {
+  prefix.len() >= 3
+    && prefix[prefix.len() - 3..].eq_ignore_ascii_case(b"url")
+    && !prefix
+      .len()
+      .checked_sub(4)
+      .map(|i| prefix[i])
+      .is_some_and(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+}
+
+/// Minifies a stylesheet or the declarations of a `style` attribute with `lightningcss`.
+/// `None` if it can't be parsed, leaving it to the whitespace compression.
+#[cfg(feature = "css-minify")]
+fn minify_css(css: &str) -> Option<String> {
+  use lightningcss::stylesheet::{
+    MinifyOptions, ParserOptions, PrinterOptions, StyleAttribute, StyleSheet,
+  };
+
+  let printer = || PrinterOptions {
+    minify: true,
+    ..Default::default()
+  };
+  if let Ok(mut stylesheet) = StyleSheet::parse(css, ParserOptions::default()) {
+    stylesheet.minify(MinifyOptions::default()).ok()?;
+    return stylesheet.to_css(printer()).ok().map(|res| res.code);
+  }
+  let mut attribute = StyleAttribute::parse(css, ParserOptions::default()).ok()?;
+  attribute.minify(MinifyOptions::default());
+  attribute.to_css(printer()).ok().map(|res| res.code)
 }
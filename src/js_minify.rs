@@ -0,0 +1,245 @@
+//! A conservative JavaScript minifier: the comments, indentation and blank lines are removed and
+//! the other whitespace is collapsed, keeping the line breaks automatic semicolon insertion may
+//! depend on. Strings, template literals and regular expressions are copied as they are.
+
+/// The keywords a regular expression may follow, unlike a division.
+static REGEX_KEYWORDS: &[&str] = &[
+  "return",
+  "typeof",
+  "instanceof",
+  "in",
+  "of",
+  "new",
+  "delete",
+  "void",
+  "throw",
+  "case",
+  "do",
+  "else",
+  "yield",
+  "await",
+];
+
+/// What the last token emitted was, to tell a regular expression from a division.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Last {
+  /// Nothing was emitted yet.
+  Start,
+  /// An identifier, a number, a property name or a postfix `++`/`--`, which a division follows.
+  Operand,
+  /// A keyword a regular expression may follow.
+  Keyword,
+  /// A string, template literal or regular expression.
+  Literal,
+  /// A punctuator.
+  Punctuator(u8),
+}
+
+impl Last {
+  fn allows_regex(self) -> bool {
+    match self {
+      Last::Start | Last::Keyword => true,
+      Last::Operand | Last::Literal => false,
+      Last::Punctuator(c) => !self.is_ambiguous() && c != b')',
+    }
+  }
+
+  /// Whether a `/` may start a regular expression as well as be a division: after `)` (`if (x)
+  /// /a/.test(s)` or `(a + b) / 2`), `]` or `}`.
+  fn is_ambiguous(self) -> bool {
+    matches!(self, Last::Punctuator(b')' | b']' | b'}'))
+  }
+}
+
+fn is_word(c: u8) -> bool {
+  c.is_ascii_alphanumeric() || matches!(c, b'_' | b'$' | b'\\') || !c.is_ascii()
+}
+
+/// The end of the string whose quote is at `start`.
+fn skip_string(bytes: &[u8], start: usize) -> usize {
+  let mut i = start + 1;
+  while i < bytes.len() && bytes[i] != bytes[start] {
+    if bytes[i] == b'\\' {
+      i += 1;
+    }
+    i += 1;
+  }
+  (i + 1).min(bytes.len())
+}
+
+/// The end of the template literal whose backtick is at `start`, with its substitutions.
+fn skip_template(bytes: &[u8], start: usize) -> usize {
+  let mut i = start + 1;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'\\' => i += 1,
+      b'`' => return i + 1,
+      b'$' if bytes.get(i + 1) == Some(&b'{') => {
+        let mut depth = 0;
+        i += 1;
+        while i < bytes.len() {
+          match bytes[i] {
+            b'"' | b'\'' => {
+              i = skip_string(bytes, i);
+              continue;
+            }
+            b'`' => {
+              i = skip_template(bytes, i);
+              continue;
+            }
+            b'{' => depth += 1,
+            b'}' => {
+              depth -= 1;
+              if depth == 0 {
+                break;
+              }
+            }
+            _ => {}
+          }
+          i += 1;
+        }
+      }
+      _ => {}
+    }
+    i += 1;
+  }
+  bytes.len()
+}
+
+/// The end of the regular expression whose slash is at `start`, before its flags.
+fn skip_regex(bytes: &[u8], start: usize) -> usize {
+  let mut i = start + 1;
+  let mut class = false;
+  while i < bytes.len() && bytes[i] != b'\n' {
+    match bytes[i] {
+      b'\\' => i += 1,
+      b'[' => class = true,
+      b']' => class = false,
+      b'/' if !class => return i + 1,
+      _ => {}
+    }
+    i += 1;
+  }
+  i.min(bytes.len())
+}
+
+/// Minifies `script`.
+pub fn minify(script: &str) -> String {
+  let bytes = script.as_bytes();
+  let mut out = String::with_capacity(script.len());
+  let mut last = Last::Start;
+  let (mut space, mut newline) = (false, false);
+  let mut i = 0;
+  while i < bytes.len() {
+    let c = bytes[i];
+    let end = match c {
+      b'\n' | b'\r' => {
+        newline = true;
+        i += 1;
+        continue;
+      }
+      c if c.is_ascii_whitespace() => {
+        space = true;
+        i += 1;
+        continue;
+      }
+      b'/' if bytes.get(i + 1) == Some(&b'/') => {
+        while i < bytes.len() && bytes[i] != b'\n' {
+          i += 1;
+        }
+        continue;
+      }
+      b'/' if bytes.get(i + 1) == Some(&b'*') => {
+        let end = script[i + 2..]
+          .find("*/")
+          .map_or(bytes.len(), |end| i + 2 + end + 2);
+        let comment = &script[i..end];
+        // license comments are kept
+        if !(comment.starts_with("/*!") || comment.contains("@license")) {
+          if comment.contains('\n') {
+            newline = true;
+          } else {
+            space = true;
+          }
+          i = end;
+          continue;
+        }
+        end
+      }
+      b'"' | b'\'' => skip_string(bytes, i),
+      b'`' => skip_template(bytes, i),
+      b'/' if last.allows_regex() => skip_regex(bytes, i),
+      // the rest of the line is kept as is, which holds the whole regular expression if it is
+      // one, and the rest of the script when the line may open a multi-line template or comment
+      b'/' if last.is_ambiguous() => {
+        let end = script[i..].find('\n').map_or(bytes.len(), |end| i + end);
+        let line = script[i..end].trim_end_matches('\r');
+        if line.contains('`') || line.contains("/*") || line.ends_with('\\') {
+          bytes.len()
+        } else {
+          end
+        }
+      }
+      b'+' | b'-' if bytes.get(i + 1) == Some(&c) => i + 2,
+      c if is_word(c) => {
+        let mut end = i + 1;
+        while end < bytes.len() && is_word(bytes[end]) {
+          end += 1;
+        }
+        end
+      }
+      _ => i + 1,
+    };
+    // never split a multi-byte character
+    let end = (end..=bytes.len())
+      .find(|end| script.is_char_boundary(*end))
+      .unwrap_or(bytes.len());
+
+    let previous = out.as_bytes().last().copied();
+    if let Some(previous) = previous {
+      if newline
+        && !matches!(previous, b'{' | b';' | b',' | b'(' | b'[' | b'\n')
+        && !matches!(c, b')' | b']' | b'}' | b';' | b',')
+      {
+        out.push('\n');
+      } else if (space || newline)
+        && ((is_word(previous) && (is_word(c) || (c == b'.' && previous.is_ascii_digit())))
+          || (previous == c && matches!(c, b'+' | b'-' | b'/'))
+          || (previous == b'/' && c == b'*'))
+      {
+        out.push(' ');
+      }
+    }
+    space = false;
+    newline = false;
+
+    let token = &script[i..end];
+    out.push_str(token);
+    last = match c {
+      b'"' | b'\'' | b'`' => Last::Literal,
+      // after a line kept as is, the next `/` is ambiguous too
+      b'/' if last.is_ambiguous() && !token.starts_with("/*") => Last::Punctuator(b')'),
+      b'/' if token.len() > 1 && !token.starts_with("/*") => Last::Literal,
+      // an increment or decrement following its operand
+      b'+' | b'-'
+        if token.len() == 2
+          && matches!(
+            last,
+            Last::Operand | Last::Literal | Last::Punctuator(b')' | b']')
+          ) =>
+      {
+        Last::Operand
+      }
+      // a keyword is a property name after a `.` (`a.in`)
+      c if is_word(c) && REGEX_KEYWORDS.contains(&token) && last != Last::Punctuator(b'.') => {
+        Last::Keyword
+      }
+      c if is_word(c) => Last::Operand,
+      // a license comment
+      b'/' if token.len() > 1 => last,
+      c => Last::Punctuator(c),
+    };
+    i = end;
+  }
+  out
+}
@@ -1,10 +1,13 @@
 #[macro_use]
 extern crate html5ever;
 
+#[cfg(feature = "font-subset")]
+use std::{collections::BTreeSet, ops::RangeInclusive};
 use std::{
-  collections::HashMap,
-  fs,
+  collections::{HashMap, HashSet},
+  fmt, fs,
   path::{Path, PathBuf},
+  sync::{Arc, Mutex},
 };
 
 use kuchiki::traits::TendrilSink;
@@ -12,9 +15,30 @@ use once_cell::sync::Lazy;
 use url::Url;
 
 mod binary;
+mod css_rules;
+#[cfg(any(feature = "font-subset", feature = "woff2"))]
+mod font;
+mod html;
+mod ico;
 mod js_css;
+#[cfg(feature = "js-minify")]
+mod js_minify;
+mod manifest;
+mod modules;
+mod report;
+mod rng;
+#[cfg(feature = "async")]
+mod stream;
+mod svg;
+pub mod tauri_integration;
+
+pub use report::{Asset, InlineEvent, Report, Warning, WarningKind};
+#[cfg(feature = "async")]
+pub use stream::{inline_file_stream, InlineOutput};
 
 static FONT_EXTENSIONS: &[&str] = &[".eot", ".woff2", ".woff", ".tff"];
+/// Attribute recording where an element's inlined content came from.
+pub(crate) static ANNOTATION_ATTRIBUTE: &str = "data-inlined-from";
 
 /// Inliner error types.
 #[derive(Debug, thiserror::Error)]
@@ -27,14 +51,459 @@ pub enum Error {
   Io(#[from] std::io::Error),
   #[error("http request error: `{0}`")]
   HttpRequest(#[from] reqwest::Error),
+  /// Inlining `{0}` would push the document over `Config::max_output_size`
+  #[error("inlining `{0}` exceeds the maximum output size")]
+  OutputSizeExceeded(String),
+  /// The selector passed to `inline_subtree()` or of `Config::extra_targets` could not be parsed
+  #[error("invalid selector `{0}`")]
+  InvalidSelector(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// What happens once inlining an asset would exceed `Config::max_output_size`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputSizePolicy {
+  /// Stop embedding assets and leave the remaining references untouched.
+  Skip,
+  /// Abort with `Error::OutputSizeExceeded`.
+  Strict,
+}
+
+/// How HTML comments are handled in the output.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CommentPolicy {
+  /// Keep every comment.
+  Preserve,
+  /// Remove every comment.
+  Strip,
+  /// Remove every comment except IE conditional comments.
+  StripExceptConditional,
+}
+
+/// What happens to IE conditional comments (`<!--[if lt IE 9]>...<![endif]-->`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConditionalComments {
+  /// Leave them untouched; their assets stay external.
+  Keep,
+  /// Inline the assets referenced in their body.
+  Inline,
+  /// Remove them, along with their body. Only the markers of the downlevel-revealed form
+  /// (`<!--[if !IE]><!-->...<!--<![endif]-->`) are removed, as its content targets other browsers.
+  Strip,
+}
+
+/// How much the output is compressed, applied to the HTML whitespace and the inlined CSS.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransformLevel {
+  /// Leave the whitespace and the CSS untouched (besides CSS comments) for byte-stable,
+  /// reviewable output.
+  None,
+  /// Collapse runs of spaces, except inside `<pre>`, `<textarea>`, `<script>` and `<style>`,
+  /// and compress the CSS: minified with the `css-minify` feature, whitespace collapsed otherwise.
+  /// The inlined scripts are minified with the `js-minify` feature.
+  Safe,
+  /// Collapse every run of whitespace, including line breaks, except inside `<pre>` and `<textarea>`.
+  /// Styles have their whitespace collapsed outside of their strings, and scripts are left to the
+  /// `js-minify` feature.
+  Aggressive,
+}
+
+/// What happens to a `<script>` whose contents were already inlined earlier in the document.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DuplicateScripts {
+  /// Inline every occurrence, like a browser executing each reference.
+  Inline,
+  /// Remove the later occurrences.
+  Remove,
+  /// Replace the later occurrences with a comment naming the first one.
+  Comment,
+}
+
+/// How `.ico` files are inlined.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum IcoMode {
+  /// Embed the whole file, with every size it holds, as `image/x-icon`.
+  Keep,
+  /// Embed only its largest image as a PNG, converting it when it's a 32-bit bitmap.
+  /// Files holding other bitmaps are kept whole.
+  LargestPng,
+}
+
+/// Which `<source>` elements of a `<video>` or `<audio>` are inlined.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MediaSources {
+  /// Inline every source under the size limits.
+  All,
+  /// Inline the first source that can be inlined and remove the other ones.
+  First,
+  /// Like `First`, but the inlined source becomes the `src` of the media element,
+  /// removing every `<source>`.
+  Collapse,
+}
+
+/// What happens to `nomodule` fallback scripts in documents that also load `type="module"` scripts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NomodulePolicy {
+  /// Keep both the module and the fallback builds.
+  Keep,
+  /// Only keep the module build, for targets that support ES modules such as Tauri's webview.
+  Remove,
+}
+
+/// The asset handed to `Config::asset_transform`.
+#[derive(Debug)]
+pub struct AssetInfo<'a> {
+  /// The path or URL the asset was loaded from.
+  pub path: &'a str,
+  /// The content type the asset is embedded with, when known.
+  pub content_type: Option<&'a str>,
+}
+
+type AssetTransformFn = dyn Fn(&AssetInfo<'_>, Vec<u8>) -> Vec<u8> + Send + Sync;
+
+/// A hook transforming an asset's bytes after it is loaded and before it is embedded.
+#[derive(Clone)]
+pub struct AssetTransform(Arc<AssetTransformFn>);
+
+impl AssetTransform {
+  pub fn new<F: Fn(&AssetInfo<'_>, Vec<u8>) -> Vec<u8> + Send + Sync + 'static>(f: F) -> Self {
+    Self(Arc::new(f))
+  }
+}
+
+impl fmt::Debug for AssetTransform {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("AssetTransform")
+  }
+}
+
+/// A source of assets, e.g. the assets embedded in an application binary.
+pub trait Resolver: Send + Sync {
+  /// Loads the asset referenced by `path` (a path relative to the document, or a URL),
+  /// or returns `None` to defer to the next source of `Config::sources`.
+  fn resolve(&self, path: &str) -> Result<Option<Vec<u8>>>;
+}
+
+/// A `Resolver` shared by the configs using it.
+#[derive(Clone)]
+pub struct AssetResolver(Arc<dyn Resolver>);
+
+impl AssetResolver {
+  pub fn new<R: Resolver + 'static>(resolver: R) -> Self {
+    Self(Arc::new(resolver))
+  }
+}
+
+impl fmt::Debug for AssetResolver {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("AssetResolver")
+  }
+}
+
+/// Where assets are loaded from, see `Config::sources`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Source {
+  /// `Config::resolver`, for both paths and URLs.
+  Resolver,
+  /// Files relative to the document's root, for paths.
+  Filesystem,
+  /// HTTP requests, for `http` and `https` URLs when `Config::inline_remote` is set.
+  Http,
+}
+
+/// A hook notified of the progress of a run, e.g. to render per-asset progress.
+#[derive(Clone)]
+pub struct EventHook(Arc<dyn Fn(&InlineEvent) + Send + Sync>);
+
+impl EventHook {
+  pub fn new<F: Fn(&InlineEvent) + Send + Sync + 'static>(f: F) -> Self {
+    Self(Arc::new(f))
+  }
+}
+
+impl fmt::Debug for EventHook {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("EventHook")
+  }
+}
+
+/// An asset cache that can be shared by several runs, e.g. when inlining every page of an app.
+/// Entries are keyed by the settings they were computed with, so runs with different configs
+/// sharing the cache don't see each other's results.
+#[derive(Debug, Clone, Default)]
+pub struct AssetCache(Arc<Mutex<HashMap<String, Option<String>>>>);
+
+impl AssetCache {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Removes every entry, e.g. once the assets changed on disk.
+  pub fn clear(&self) {
+    self.0.lock().unwrap().clear();
+  }
+}
+
+/// What happens to `<link rel="preload">` and `<link rel="modulepreload">` elements.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PreloadPolicy {
+  /// Remove the preloads of assets inlined in the document, and inline the targets of the
+  /// other `image` and `font` preloads (e.g. used by scripts) so they don't cause a request.
+  Inline,
+  /// Leave the preloads untouched, unless `Config::remove_resource_hints` is set.
+  Keep,
+}
+
+/// Whether the query string of a reference is kept when the asset is loaded.
+/// The fragment is always removed. Kept queries are part of the cache key.
+#[derive(Clone)]
+pub enum QueryPolicy {
+  /// Strip the query of local files and keep it for remote URLs (signed URLs, cache busting).
+  StripLocal,
+  /// Always strip the query.
+  Strip,
+  /// Always keep the query.
+  Preserve,
+  /// Keep the query of the references for which the callback returns `true`.
+  Custom(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+impl QueryPolicy {
+  fn keeps_query(&self, path: &str) -> bool {
+    match self {
+      Self::StripLocal => Url::parse(path).is_ok(),
+      Self::Strip => false,
+      Self::Preserve => true,
+      Self::Custom(keep) => keep(path),
+    }
+  }
+}
+
+impl fmt::Debug for QueryPolicy {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::StripLocal => f.write_str("StripLocal"),
+      Self::Strip => f.write_str("Strip"),
+      Self::Preserve => f.write_str("Preserve"),
+      Self::Custom(_) => f.write_str("Custom"),
+    }
+  }
+}
+
+/// A device the `apple-touch-startup-image` links are kept for, see
+/// `Config::startup_image_devices`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DeviceProfile {
+  /// Width of the screen in portrait orientation, in CSS pixels.
+  pub width: u32,
+  /// Height of the screen in portrait orientation, in CSS pixels.
+  pub height: u32,
+  pub pixel_ratio: f32,
+}
+
+/// How the inlined fonts are subset, see `Config::font_subset`.
+#[cfg(feature = "font-subset")]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct FontSubset {
+  /// Code points kept on top of the characters of the document, e.g. `0x20..=0x7e` for the text
+  /// inserted by scripts or by the CSS `content` property.
+  pub unicode_ranges: Vec<RangeInclusive<u32>>,
+}
+
+/// What happens to an icon `<link>` (`icon`, `apple-touch-icon`, ...) or tile image `<meta>`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IconPolicy {
+  /// Inline the icon as a data URI.
+  Inline,
+  /// Remove the link, e.g. in webviews where icons are unused.
+  Remove,
+  /// Leave the link untouched.
+  Keep,
+}
+
+/// A pattern matching the references that are never inlined.
+#[derive(Debug, Clone)]
+pub enum ExcludePattern {
+  /// A glob matched against the whole reference, ignoring its query and fragment:
+  /// `*` matches any sequence of characters (including `/`) and `?` a single one,
+  /// e.g. `*.hot-update.js`.
+  Glob(String),
+  /// A regex searched in the reference, e.g. `^https://cdn\.example\.com/video/`.
+  Regex(regex::Regex),
+}
+
+impl ExcludePattern {
+  fn to_regex(&self) -> regex::Regex {
+    match self {
+      Self::Glob(glob) => {
+        let mut pattern = String::from("^");
+        for c in glob.chars() {
+          match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push_str("[^?#]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+          }
+        }
+        pattern.push_str("(?:[?#].*)?$");
+        regex::Regex::new(&pattern).unwrap()
+      }
+      Self::Regex(regex) => regex.clone(),
+    }
+  }
+}
+
+/// What happens to `<use>` elements referencing a symbol of an external SVG sprite
+/// (`<use href="icons.svg#home">`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SvgSprites {
+  /// Copy the referenced symbols to a hidden sprite at the start of the body and reference them
+  /// by id. Definitions (`<defs>`) of the sprite outside of the symbols aren't copied.
+  Block,
+  /// Replace each `<use>` with the content of its symbol.
+  Replace,
+  /// Leave the references untouched.
+  Keep,
+}
+
+/// Where the inlined `defer` scripts go, since an inline script can't be deferred.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeferredScripts {
+  /// Move them to the end of the body: they run once the document is parsed, but before the
+  /// deferred scripts left external and the module scripts, which may change their order.
+  EndOfBody,
+  /// Keep them deferred in place with their content as a `data:` URI `src`, so every deferred
+  /// script runs in the order of the original document. A `Content-Security-Policy` must allow
+  /// `data:` scripts or carry a nonce, see `Config::csp_nonce`.
+  DataUri,
+}
+
+/// What happens to the `navigator.serviceWorker.register()` calls of the inline scripts, whose
+/// service worker can't be served to a single-file document.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ServiceWorkers {
+  /// Leave the registrations untouched.
+  Keep,
+  /// Replace each registration with a promise that never settles, so the code chained to it
+  /// doesn't run.
+  Remove,
+  /// Leave the registrations untouched and report them as `WarningKind::ServiceWorker`.
+  Warn,
+}
+
+/// What happens to the `sourceMappingURL` comments of the inlined scripts and stylesheets, whose
+/// source maps would resolve next to the document.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SourceMapPolicy {
+  /// Remove the comments.
+  Remove,
+  /// Embed the source maps as data URIs; the comments referencing a source map that can't be
+  /// loaded are removed.
+  Inline,
+  /// Leave the comments untouched.
+  Keep,
+}
+
+/// What happens to the scripts and stylesheets whose content doesn't match their `integrity`
+/// metadata (`sha256`, `sha384` or `sha512`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IntegrityPolicy {
+  /// Leave the asset external, as the browser would refuse to run it, and report
+  /// `WarningKind::IntegrityMismatch`.
+  Enforce,
+  /// Inline the asset anyway and report `WarningKind::IntegrityMismatch`.
+  Warn,
+  /// Inline the asset without checking its integrity.
+  Ignore,
+}
+
+/// The nonce of the `<script>` and `<style>` elements, so the output still satisfies a nonce-based
+/// `Content-Security-Policy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CspNonce {
+  /// Apply the nonce of the first `<script>` or `<style>` carrying one to the generated ones, e.g.
+  /// the loader of `Config::critical_css`.
+  Document,
+  /// Apply this nonce to the generated elements and to the ones carrying a nonce, and replace the
+  /// `'nonce-...'` sources of the `<meta http-equiv="Content-Security-Policy">` elements with it.
+  Value(String),
+  /// Same as `CspNonce::Value` with a random nonce, see `Config::seed`.
+  Random,
+}
+
+/// How `<img>` elements referencing an SVG file are inlined.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SvgMode {
+  /// Embed the file as a data URI, like other images.
+  DataUri,
+  /// Replace the `<img>` with the file's `<svg>` element, so it can be styled with CSS
+  /// (e.g. `currentColor`). The `width`, `height`, `class`, `id` and `style` of the `<img>` are
+  /// carried over and its `alt` becomes an `aria-label`.
+  /// Ids inside the file are kept as is, so they may collide when it's inlined more than once.
+  InlineElement,
+}
+
+/// How the CSS `url()` references to local files larger than `Config::max_inline_size` are
+/// rewritten, so they still resolve once the document is moved. References to remote files become
+/// their absolute URL with any policy but `Keep`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OversizedUrls {
+  /// Leave the reference as written.
+  Keep,
+  /// Reference the file by its absolute `file:` URL.
+  FileUrl,
+  /// Reference the file by its path relative to the document, appended to this prefix
+  /// (e.g. `https://cdn.example.com/app/`).
+  Prefix(String),
+  /// Copy the file to this directory, at its path relative to the document, which it's referenced
+  /// by: the output document is meant to be written to the directory.
+  CopyTo(PathBuf),
+}
+
+/// The browsers the output document is meant for, centralizing the compatibility trade-offs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Target {
+  /// Any browser: keeps every fallback and only emits base64 data URIs.
+  Legacy,
+  /// Evergreen webviews (WebView2, WKWebView, WebKitGTK): removes `nomodule` scripts and
+  /// non-woff2 font fallbacks, and emits percent-encoded SVG data URIs, which compress better.
+  Modern,
+}
+
+impl Target {
+  /// Whether `nomodule` fallback scripts may run on the target.
+  pub(crate) fn runs_nomodule(self) -> bool {
+    self == Target::Legacy
+  }
+
+  /// Whether `@font-face` sources other than woff2 may be used by the target.
+  pub(crate) fn needs_font_fallbacks(self) -> bool {
+    self == Target::Legacy
+  }
+
+  /// Whether the target accepts SVG data URIs that are not base64 encoded.
+  pub(crate) fn supports_utf8_svg(self) -> bool {
+    self == Target::Modern
+  }
+}
+
+/// Characters escaped in percent-encoded SVG data URIs, so they are valid in quoted or unquoted
+/// CSS `url()`s and in HTML attributes.
+const SVG_DATA_URI_ESCAPES: &percent_encoding::AsciiSet = &percent_encoding::CONTROLS
+  .add(b' ')
+  .add(b'"')
+  .add(b'#')
+  .add(b'%')
+  .add(b'\'')
+  .add(b'(')
+  .add(b')')
+  .add(b'<')
+  .add(b'>');
+
 /// Config struct that is passed to `inline_file()` and `inline_html_string()`
 ///
 /// Default enables everything
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct Config {
   /// Whether or not to inline fonts in the css as base64.
   pub inline_fonts: bool,
@@ -42,6 +511,175 @@ pub struct Config {
   pub inline_remote: bool,
   /// Maximum size of files that will be inlined, in bytes
   pub max_inline_size: usize,
+  /// How the CSS `url()` references to the files over `max_inline_size` are rewritten.
+  /// Defaults to `OversizedUrls::Keep`.
+  pub oversized_urls: OversizedUrls,
+  /// Maximum size of the whole output document, in bytes. `None` means unlimited.
+  pub max_output_size: Option<usize>,
+  /// How `max_output_size` is enforced.
+  pub output_size_policy: OutputSizePolicy,
+  /// Maximum nesting of CSS `@import` rules that will be inlined.
+  /// Deeper imports are left as `@import` rules, moved to the top of the flattened stylesheet
+  /// with the conditions of the imports around them.
+  pub max_import_depth: usize,
+  /// What to do with HTML comments.
+  pub comments: CommentPolicy,
+  /// How much the output is compressed.
+  pub transform_level: TransformLevel,
+  /// What to do with scripts whose contents were already inlined.
+  pub duplicate_scripts: DuplicateScripts,
+  /// Whether to record the original URL of inlined elements in a `data-inlined-from` attribute.
+  pub annotate_sources: bool,
+  /// What to do with `nomodule` fallback scripts.
+  pub nomodule: NomodulePolicy,
+  /// Where the inlined `defer` scripts go. Defaults to `DeferredScripts::EndOfBody`.
+  pub deferred_scripts: DeferredScripts,
+  /// Hook invoked on every loaded asset before it is embedded, e.g. to minify or substitute variables.
+  pub asset_transform: Option<AssetTransform>,
+  /// Whether to remove `preload`, `modulepreload`, `prefetch`, `preconnect` and `dns-prefetch` links
+  /// whose targets were all inlined.
+  pub remove_resource_hints: bool,
+  /// What to do with `<link rel="preload">` and `<link rel="modulepreload">` elements.
+  /// Defaults to `PreloadPolicy::Inline`.
+  pub preloads: PreloadPolicy,
+  /// Tokens replaced in every inlined text asset (JS, CSS), e.g. `__APP_VERSION__` => `"1.0.0"`.
+  pub defines: HashMap<String, String>,
+  /// Policy for icon links keyed by their lowercase `rel` (e.g. `apple-touch-startup-image`), and
+  /// for tile image `<meta>` tags keyed by their lowercase `name` (e.g. `msapplication-tileimage`).
+  /// Icons without an entry are inlined.
+  pub icon_links: HashMap<String, IconPolicy>,
+  /// The devices the `apple-touch-startup-image` links are kept for, in both orientations;
+  /// the links whose `media` matches none of them are removed. `None`, the default, keeps every
+  /// link.
+  pub startup_image_devices: Option<Vec<DeviceProfile>>,
+  /// Whether the social preview images of `<meta property="og:image">` and
+  /// `<meta name="twitter:image">` are inlined, e.g. for tools rendering the previews locally.
+  /// Crawlers don't load data URIs, so this is off by default.
+  pub inline_social_images: bool,
+  /// Which `<source>` elements of a `<video>` or `<audio>` are inlined. Defaults to
+  /// `MediaSources::All`.
+  pub media_sources: MediaSources,
+  /// How `.ico` files (favicons) are inlined. Defaults to `IcoMode::Keep`.
+  pub ico_mode: IcoMode,
+  /// Reduces the inlined TTF and OTF fonts to the glyphs of the characters in the document's text
+  /// (and `alt`, `title`, `placeholder` and `value` attributes). WOFF and WOFF2 fonts are inlined
+  /// whole. `None`, the default, inlines every font whole.
+  #[cfg(feature = "font-subset")]
+  pub font_subset: Option<FontSubset>,
+  /// Whether the inlined TTF and OTF fonts are converted to WOFF2, which is much smaller; the
+  /// `format()` hints of their `@font-face` sources are updated.
+  #[cfg(feature = "woff2")]
+  pub convert_fonts_to_woff2: bool,
+  /// Attributes holding the real URL of lazy-loaded assets (lazysizes, lozad), inlined like `src`.
+  /// Attributes ending with `srcset` are inlined as a `srcset`. Empty by default, since these
+  /// attributes often hold templating placeholders or script state rather than URLs; e.g.
+  /// `vec!["data-src".into(), "data-srcset".into(), "data-background".into()]` for lazysizes.
+  pub lazy_attributes: Vec<String>,
+  /// Whether the inlined lazy attributes replace the attribute they stand for, so the document
+  /// works without the lazy-loading script: `data-src` becomes `src`, and `data-background`
+  /// a `background-image` style.
+  pub promote_lazy_attributes: bool,
+  /// Additional `(selector, attribute)` pairs holding asset URLs, e.g. for custom elements
+  /// (`("app-avatar", "image")`). Attributes ending with `srcset` are inlined as a `srcset`.
+  pub extra_targets: Vec<(String, String)>,
+  /// What to do with IE conditional comments. Defaults to `ConditionalComments::Keep`.
+  pub conditional_comments: ConditionalComments,
+  /// Maximum size of the files linked by `<a href download>` that are inlined, in bytes.
+  /// `None`, the default, leaves them external.
+  pub max_download_size: Option<usize>,
+  /// Whether query strings are kept when loading assets.
+  pub query: QueryPolicy,
+  /// Whether the document's `<base>` element is removed when every asset it applied to was inlined.
+  /// Note that the base also applies to links, which will resolve against the document itself.
+  pub remove_base: bool,
+  /// Whether the `http` references remaining in a document with an `https` `<base>` are upgraded
+  /// to `https`. They are reported as `WarningKind::MixedContent` otherwise.
+  pub upgrade_insecure_requests: bool,
+  /// The browsers the output is meant for. `Config::nomodule` can still remove the `nomodule`
+  /// scripts for the legacy target.
+  pub target: Target,
+  /// References that are never inlined, checked before any filesystem or network access.
+  pub exclude: Vec<ExcludePattern>,
+  /// A cache shared with other runs; each run only caches its assets by default.
+  pub cache: Option<AssetCache>,
+  /// Hook notified as every asset reference is resolved.
+  pub on_event: Option<EventHook>,
+  /// Loads the assets before the other sources by default, see `Config::sources`.
+  pub resolver: Option<AssetResolver>,
+  /// The sources an asset is looked up in, in order; the first one that has it wins.
+  /// Sources that aren't listed are disabled.
+  pub sources: Vec<Source>,
+  /// Seeds the random choices of a run (generated nonces and ids, tie-breaking) so the output is
+  /// reproducible; a random seed is used otherwise.
+  pub seed: Option<u64>,
+  /// How many levels of nested `<iframe src>` documents are inlined into `srcdoc` attributes.
+  /// Disabled (`0`) by default since `srcdoc` documents share the origin of their parent.
+  pub max_iframe_depth: usize,
+  /// What to do with references to external SVG sprites.
+  pub svg_sprites: SvgSprites,
+  /// How `<img>` elements referencing an SVG file are inlined. Defaults to `SvgMode::DataUri`.
+  pub svg_mode: SvgMode,
+  /// Whether only the CSS rules matching an element of the document are applied right away: the
+  /// other rules of every `<style>` are moved to a `<style media="not all">` after it, which a
+  /// script enables once the document is loaded. Selectors are matched without their
+  /// pseudo-classes, and rules that can't be evaluated are kept.
+  pub critical_css: bool,
+  /// Whether the CSS rules whose selectors match no element of the document are removed from
+  /// every `<style>`, e.g. to shrink framework stylesheets. Selectors are matched without their
+  /// pseudo-classes, and rules that can't be evaluated are kept.
+  pub purge_css: bool,
+  /// Patterns searched in the selectors of the rules `Config::purge_css` always keeps, for the
+  /// elements created by scripts (e.g. `\.modal`, `^\.toast-`).
+  pub purge_safelist: Vec<regex::Regex>,
+  /// Whether the `<style>` elements, e.g. the ones replacing every `<link>`, are merged into one,
+  /// their `media` becoming an `@media` rule. The stylesheets left external and the `<style>`
+  /// elements with other attributes (`nonce`, `title`, ...) stay in place and break the runs of
+  /// merged elements, to keep the cascade order.
+  pub merge_styles: bool,
+  /// Whether the consecutive classic scripts inlined from files, with the same attributes and no
+  /// `async`, are concatenated into one `<script>`. An uncaught error then stops the rest of the
+  /// merged script, and the scripts with a `"use strict"` directive are kept apart.
+  pub concat_scripts: bool,
+  /// Whether the `<script type="module">` elements are inlined along with the modules they
+  /// import with relative or `http(s)` specifiers, which are embedded as data URIs. Bare
+  /// specifiers (`import "vue"`) are left to the import map.
+  pub bundle_modules: bool,
+  /// Whether the `import()` calls of the bundled modules with a string literal specifier are
+  /// bundled too, e.g. for code-split builds. The chunks importing the module that loads them
+  /// are left as is, as a data URI can't reference its importer.
+  pub bundle_dynamic_imports: bool,
+  /// Whether the `<script type="importmap">` elements are removed once every bare specifier of
+  /// the bundled modules was resolved with them and bundled. The `import()` calls whose specifier
+  /// isn't a string literal can't be checked and must not rely on the import map.
+  pub remove_import_map: bool,
+  /// Whether the local scripts of the `new Worker("...")` and `new SharedWorker("...")` calls of
+  /// the inline scripts are embedded as Blob URLs. Only string literals are rewritten, and
+  /// the relative URLs of the workers (`importScripts()`) no longer resolve. Shared workers are no
+  /// longer shared across documents, each one creating its own Blob URL.
+  pub inline_workers: bool,
+  /// Whether the local WebAssembly modules of the `fetch("....wasm")` calls of the inline scripts
+  /// are embedded as base64, the call resolving to a `Response` of the decoded bytes, e.g. for
+  /// `WebAssembly.instantiateStreaming(fetch("app.wasm"))`. Only string literals are rewritten,
+  /// and `Config::max_inline_size` applies.
+  pub inline_wasm: bool,
+  /// Patterns of the string literals of the inline scripts that are replaced with the data URI of
+  /// the local file they reference, for the assets loaded imperatively (`img.src = "logo.png"`),
+  /// e.g. `^assets/.+\.(png|svg)$`. Only relative paths to a file under the root are replaced.
+  pub script_asset_patterns: Vec<regex::Regex>,
+  /// What happens to the service worker registrations of the inline scripts. Defaults to
+  /// `ServiceWorkers::Keep`.
+  pub service_workers: ServiceWorkers,
+  /// What happens to the `sourceMappingURL` comments of the inlined scripts and of every
+  /// processed stylesheet, linked or inline. Defaults to `SourceMapPolicy::Remove`, which strips
+  /// them.
+  pub source_maps: SourceMapPolicy,
+  /// What happens to the scripts and stylesheets whose content doesn't match their `integrity`
+  /// attribute, which is dropped from the inlined elements. Defaults to `IntegrityPolicy::Enforce`.
+  pub integrity: IntegrityPolicy,
+  /// The nonce of the `<script>` and `<style>` elements created or replaced while inlining. When the
+  /// document's `Content-Security-Policy` relies on nonces, the external scripts and stylesheets
+  /// without one are left external. Defaults to `CspNonce::Document`.
+  pub csp_nonce: CspNonce,
 }
 
 impl Default for Config {
@@ -51,6 +689,62 @@ impl Default for Config {
       inline_fonts: true,
       inline_remote: true,
       max_inline_size: 5000,
+      oversized_urls: OversizedUrls::Keep,
+      max_output_size: None,
+      output_size_policy: OutputSizePolicy::Skip,
+      max_import_depth: 10,
+      comments: CommentPolicy::Preserve,
+      transform_level: TransformLevel::Safe,
+      duplicate_scripts: DuplicateScripts::Inline,
+      annotate_sources: false,
+      nomodule: NomodulePolicy::Keep,
+      deferred_scripts: DeferredScripts::EndOfBody,
+      asset_transform: None,
+      remove_resource_hints: false,
+      preloads: PreloadPolicy::Inline,
+      defines: HashMap::new(),
+      icon_links: HashMap::new(),
+      startup_image_devices: None,
+      inline_social_images: false,
+      media_sources: MediaSources::All,
+      ico_mode: IcoMode::Keep,
+      #[cfg(feature = "font-subset")]
+      font_subset: None,
+      #[cfg(feature = "woff2")]
+      convert_fonts_to_woff2: false,
+      lazy_attributes: vec![],
+      promote_lazy_attributes: false,
+      extra_targets: vec![],
+      conditional_comments: ConditionalComments::Keep,
+      max_download_size: None,
+      query: QueryPolicy::StripLocal,
+      remove_base: false,
+      upgrade_insecure_requests: false,
+      target: Target::Legacy,
+      exclude: Vec::new(),
+      cache: None,
+      on_event: None,
+      resolver: None,
+      sources: vec![Source::Resolver, Source::Filesystem, Source::Http],
+      seed: None,
+      max_iframe_depth: 0,
+      svg_sprites: SvgSprites::Block,
+      svg_mode: SvgMode::DataUri,
+      critical_css: false,
+      purge_css: false,
+      purge_safelist: Vec::new(),
+      merge_styles: false,
+      concat_scripts: false,
+      bundle_modules: false,
+      bundle_dynamic_imports: false,
+      remove_import_map: false,
+      inline_workers: false,
+      inline_wasm: false,
+      script_asset_patterns: Vec::new(),
+      service_workers: ServiceWorkers::Keep,
+      source_maps: SourceMapPolicy::Remove,
+      integrity: IntegrityPolicy::Enforce,
+      csp_nonce: CspNonce::Document,
     }
   }
 }
@@ -61,60 +755,479 @@ fn content_type_map() -> &'static serde_json::Value {
   &MAP
 }
 
-fn load_path<P: AsRef<Path>>(path: &str, config: &Config, root_path: P) -> Result<Option<String>> {
-  if !config.inline_fonts && FONT_EXTENSIONS.iter().any(|f| path.ends_with(f)) {
-    log::debug!(
-      "[INLINER] `{}` is a font and config.inline_fonts == false",
-      path
-    );
-    return Ok(None);
+/// State shared by every pass of a single inlining run.
+pub(crate) struct Context {
+  pub(crate) config: Config,
+  /// The root all relative paths are evaluated with, including a relative `<base href>`.
+  pub(crate) root_path: PathBuf,
+  /// The remote `<base href>` relative references are resolved against, if any.
+  pub(crate) base_url: Option<Url>,
+  pub(crate) cache: HashMap<String, Option<String>>,
+  /// Estimated size of the output document, grown as assets are embedded.
+  pub(crate) output_size: usize,
+  /// Nesting level of the CSS `@import` currently being resolved.
+  pub(crate) import_depth: usize,
+  /// Keys (see `Context::asset_key`) of the stylesheets being inlined, outermost first.
+  pub(crate) import_chain: Vec<String>,
+  /// The conditions of the `@import`s being inlined, outermost first.
+  pub(crate) import_conditions: Vec<String>,
+  /// The `@import`s left over `Config::max_import_depth`, which go first in the flattened
+  /// stylesheet.
+  pub(crate) hoisted_imports: Vec<String>,
+  /// The import map the bare specifiers of the bundled modules are resolved with.
+  pub(crate) import_map: modules::ImportMap,
+  /// Hashes of the inlined script contents, mapped to the `src` they were first inlined from.
+  pub(crate) inlined_scripts: HashMap<u64, String>,
+  pub(crate) report: Report,
+  /// Keys (see `Context::asset_key`) of the assets that were inlined.
+  pub(crate) inlined: HashSet<String>,
+  /// Keys of the assets that were requested but remain external references.
+  pub(crate) external: HashSet<String>,
+  /// Matches any of the `Config::defines` keys, longest first.
+  defines: Option<regex::Regex>,
+  /// The compiled `Config::exclude` patterns.
+  exclude: Vec<regex::Regex>,
+  /// Hash of the settings a loaded asset depends on, prefixing the `Config::cache` keys.
+  cache_namespace: u64,
+  /// Nesting level of the iframe document being inlined.
+  pub(crate) iframe_depth: usize,
+  /// Set by `analyze()`: the run has no side effects, e.g. the copies of `OversizedUrls::CopyTo`.
+  pub(crate) dry_run: bool,
+  /// Source of the run's random choices, see `Config::seed`.
+  pub(crate) rng: rng::Rng,
+  /// Whether the document's `Content-Security-Policy` allows scripts and styles by nonce.
+  pub(crate) csp_nonces: bool,
+  /// The `<script>` and `<style>` elements created or replaced while inlining, which get the
+  /// nonce of `Config::csp_nonce`.
+  pub(crate) generated: Vec<kuchiki::NodeRef>,
+  /// Cache keys of the assets that passed a check against integrity metadata, followed by it.
+  verified: HashSet<String>,
+  /// The characters the fonts are subset to, see `Config::font_subset`.
+  #[cfg(feature = "font-subset")]
+  pub(crate) font_characters: BTreeSet<char>,
+}
+
+impl Context {
+  pub(crate) fn new(config: Config, root_path: PathBuf, html: &str) -> Self {
+    let defines = if config.defines.is_empty() {
+      None
+    } else {
+      let mut keys: Vec<&String> = config.defines.keys().collect();
+      keys.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+      let pattern = keys
+        .iter()
+        .map(|key| regex::escape(key))
+        .collect::<Vec<_>>()
+        .join("|");
+      Some(regex::Regex::new(&pattern).unwrap())
+    };
+    let exclude = config
+      .exclude
+      .iter()
+      .map(ExcludePattern::to_regex)
+      .collect();
+    Self {
+      cache_namespace: cache_namespace(&config),
+      rng: rng::Rng::new(config.seed),
+      csp_nonces: false,
+      generated: Vec::new(),
+      verified: HashSet::new(),
+      iframe_depth: 0,
+      dry_run: false,
+      defines,
+      exclude,
+      config,
+      root_path,
+      base_url: None,
+      cache: HashMap::new(),
+      output_size: html.len(),
+      import_depth: 0,
+      import_chain: Vec::new(),
+      import_conditions: Vec::new(),
+      hoisted_imports: Vec::new(),
+      import_map: modules::ImportMap::default(),
+      inlined_scripts: HashMap::new(),
+      report: Report::default(),
+      inlined: HashSet::new(),
+      external: HashSet::new(),
+      #[cfg(feature = "font-subset")]
+      font_characters: BTreeSet::new(),
+    }
   }
 
-  let raw = if let Ok(url) = Url::parse(path) {
-    if config.inline_remote {
-      let response = reqwest::blocking::Client::builder()
-        .build()?
-        .get(url)
-        .send()?;
-      if let Some(content_type) = response.headers().get(reqwest::header::CONTENT_TYPE) {
-        let content_type = content_type.to_str().unwrap();
-        if let Some(extension) = path.split('.').last() {
-          let expected_content_type = content_type_map()
-            .get(extension)
-            .map(|c| c.to_string())
-            .unwrap_or_else(|| content_type.to_string());
-          if content_type != expected_content_type {
-            log::debug!(
-              "[INLINER] `{}` response's content type is invalid; expected {} but got {}",
-              path,
-              expected_content_type,
-              content_type,
+  /// Normalizes a reference so the same asset referenced in different ways compares equal:
+  /// URLs are serialized, local paths are resolved against the root and canonicalized.
+  pub(crate) fn asset_key(&self, path: &str) -> String {
+    let path = self.resolve(path);
+    let path = path.split('#').next().unwrap_or_default();
+    if let Ok(url) = Url::parse(path) {
+      url.to_string()
+    } else {
+      let file_path = local_path(&self.root_path, path);
+      file_path
+        .canonicalize()
+        .unwrap_or(file_path)
+        .to_string_lossy()
+        .to_string()
+    }
+  }
+
+  /// Resolves a relative reference against the remote `<base href>`, if any.
+  pub(crate) fn resolve(&self, path: &str) -> String {
+    match &self.base_url {
+      Some(base)
+        if !is_empty_reference(path)
+          && Url::parse(path).is_err()
+          && !Path::new(path).is_absolute() =>
+      {
+        base
+          .join(path)
+          .map(|url| url.to_string())
+          .unwrap_or_else(|_| path.to_string())
+      }
+      _ => path.to_string(),
+    }
+  }
+
+  /// The location inline CSS (`<style>`, `style` attributes) is resolved from.
+  pub(crate) fn document_base(&self) -> String {
+    match &self.base_url {
+      Some(base) => base.to_string(),
+      None => self.root_path.to_string_lossy().to_string(),
+    }
+  }
+
+  fn emit(&self, event: InlineEvent) {
+    if let Some(hook) = &self.config.on_event {
+      (hook.0)(&event);
+    }
+  }
+
+  /// Records a resolved asset in the report and notifies `Config::on_event`.
+  pub(crate) fn record(&mut self, asset: Asset) {
+    self.emit(InlineEvent::Resolved(asset.clone()));
+    self.report.assets.push(asset);
+  }
+
+  /// The key of `path` in the caches: its `Context::asset_key`, qualified for the fonts subset to
+  /// the characters of a document.
+  fn cache_key(&self, path: &str) -> String {
+    let key = self.asset_key(path);
+    #[cfg(feature = "font-subset")]
+    if self.config.font_subset.is_some() && font::is_sfnt(extension(path)) {
+      use std::hash::{Hash, Hasher};
+
+      let mut hasher = std::collections::hash_map::DefaultHasher::new();
+      self.font_characters.hash(&mut hasher);
+      return format!("{}#subset-{:016x}", key, hasher.finish());
+    }
+    key
+  }
+
+  /// The cached result of loading `path`, from this run or from `Config::cache`.
+  /// The cache is keyed by `Context::cache_key` since nested documents (iframes) share it.
+  fn cached(&mut self, path: &str) -> Option<Option<String>> {
+    let key = self.cache_key(path);
+    if let Some(res) = self.cache.get(&key) {
+      return Some(res.clone());
+    }
+    let shared = self.config.cache.as_ref()?;
+    let shared_key = format!("{:016x}:{}", self.cache_namespace, key);
+    let res = shared.0.lock().unwrap().get(&shared_key).cloned()?;
+    self.cache.insert(key, res.clone());
+    Some(res)
+  }
+
+  fn store(&mut self, path: &str, res: Option<String>) {
+    let key = self.cache_key(path);
+    if let Some(shared) = &self.config.cache {
+      let shared_key = format!("{:016x}:{}", self.cache_namespace, key);
+      shared.0.lock().unwrap().insert(shared_key, res.clone());
+    }
+    self.cache.insert(key, res);
+  }
+
+  /// Replaces the `Config::defines` tokens in a text asset.
+  fn substitute_defines(&self, text: String) -> String {
+    match &self.defines {
+      Some(defines) => defines
+        .replace_all(&text, |caps: &regex::Captures| {
+          self.config.defines[&caps[0]].clone()
+        })
+        .to_string(),
+      None => text,
+    }
+  }
+
+  /// Logs a warning and adds it to the report.
+  pub(crate) fn warn(&mut self, kind: WarningKind, message: String) {
+    log::warn!("[INLINER] {}", message);
+    self.report.warnings.push(Warning { kind, message });
+  }
+
+  /// Records `source` on the `element` replacing it, when `Config::annotate_sources` is set.
+  pub(crate) fn annotate(&self, element: &kuchiki::NodeRef, source: &str) {
+    if self.config.annotate_sources {
+      if let Some(element) = element.as_element() {
+        element
+          .attributes
+          .borrow_mut()
+          .insert(ANNOTATION_ATTRIBUTE, source.to_string());
+      }
+    }
+  }
+
+  /// Records `element`, created or replaced while inlining, as one the CSP nonce applies to.
+  pub(crate) fn generate(&mut self, element: &kuchiki::NodeRef) {
+    self.generated.push(element.clone());
+  }
+
+  /// Accounts for embedding `len` more bytes for `path`.
+  /// Returns `Ok(false)` when the asset must be left external to honor `max_output_size`.
+  fn reserve_output(&mut self, path: &str, len: usize) -> Result<bool> {
+    if let Some(max_output_size) = self.config.max_output_size {
+      if self.output_size + len > max_output_size {
+        return match self.config.output_size_policy {
+          OutputSizePolicy::Skip => {
+            let message = format!(
+              "`{}` would exceed the max output size and will not be inlined",
+              path
             );
-            return Ok(None);
+            self.warn(WarningKind::OutputSizeExceeded, message);
+            Ok(false)
+          }
+          OutputSizePolicy::Strict => Err(Error::OutputSizeExceeded(path.to_string())),
+        };
+      }
+    }
+    self.output_size += len;
+    Ok(true)
+  }
+}
+
+/// Hashes every setting `load_path()` depends on.
+fn cache_namespace(config: &Config) -> u64 {
+  use std::hash::{Hash, Hasher};
+
+  let mut hasher = std::collections::hash_map::DefaultHasher::new();
+  config.inline_fonts.hash(&mut hasher);
+  config.inline_remote.hash(&mut hasher);
+  config.max_inline_size.hash(&mut hasher);
+  config.target.supports_utf8_svg().hash(&mut hasher);
+  let mut defines: Vec<_> = config.defines.iter().collect();
+  defines.sort();
+  defines.hash(&mut hasher);
+  // transforms can't be compared, runs only share entries when they share the same hook
+  config
+    .asset_transform
+    .as_ref()
+    .map(|transform| Arc::as_ptr(&transform.0) as *const () as usize)
+    .hash(&mut hasher);
+  config
+    .resolver
+    .as_ref()
+    .map(|resolver| Arc::as_ptr(&resolver.0) as *const () as usize)
+    .hash(&mut hasher);
+  config.sources.hash(&mut hasher);
+  config.ico_mode.hash(&mut hasher);
+  #[cfg(feature = "font-subset")]
+  config.font_subset.hash(&mut hasher);
+  #[cfg(feature = "woff2")]
+  config.convert_fonts_to_woff2.hash(&mut hasher);
+  hasher.finish()
+}
+
+/// The size of the bytes an asset was loaded from, given its embedded form.
+fn raw_size(content: &str) -> usize {
+  match content
+    .strip_prefix("data:")
+    .and_then(|data| data.split_once(','))
+  {
+    Some((parameters, data)) if parameters.ends_with(";base64") => {
+      let padding = data.bytes().rev().take_while(|b| *b == b'=').count();
+      data.len() / 4 * 3 - padding
+    }
+    Some((_, data)) => percent_encoding::percent_decode_str(data).count(),
+    None => content.len(),
+  }
+}
+
+/// Estimates the size of the output document from the `Report` of an `analyze()` run:
+/// the input document plus every inlined asset's raw size with its encoding overhead.
+/// Text assets are counted as loaded, so the estimate is higher when they are compressed.
+pub fn estimate_output_size(report: &Report) -> usize {
+  report.document_size
+    + report
+      .assets
+      .iter()
+      .filter(|asset| asset.inlined)
+      .filter_map(|asset| {
+        let raw_size = asset.raw_size?;
+        Some(match asset.content_type.as_deref() {
+          Some("text/css") | Some("text/javascript") | Some("text/html") | None => raw_size,
+          Some(content_type) => {
+            "data:;base64,".len() + content_type.len() + raw_size.div_ceil(3) * 4
+          }
+        })
+      })
+      .sum::<usize>()
+}
+
+/// Loads the raw bytes of `path` from the first of `Config::sources` that has it.
+pub(crate) fn load_raw(ctx: &Context, path: &str) -> Result<Option<Vec<u8>>> {
+  let config = &ctx.config;
+  let url = Url::parse(path).ok();
+  for source in &config.sources {
+    match (source, &url) {
+      (Source::Resolver, _) => {
+        if let Some(resolver) = &config.resolver {
+          if let Some(raw) = resolver.0.resolve(path)? {
+            log::debug!("[INLINER] `{}` loaded by the resolver", path);
+            return Ok(Some(raw));
           }
         }
       }
-      Some(response.bytes()?.as_ref().to_vec())
-    } else {
-      log::debug!(
-        "[INLINER] `{}` is a remote URL and config.inline_remote == false",
-        path
-      );
-      None
+      (Source::Filesystem, None) => {
+        let file_path = local_path(&ctx.root_path, path);
+        if file_path.is_file() {
+          log::debug!(
+            "[INLINER] loading `{:?}` with fs::read `{:?}`",
+            file_path,
+            path
+          );
+          return fs::read(file_path).map(Some).map_err(Into::into);
+        }
+      }
+      (Source::Http, Some(url)) if url.scheme() == "http" || url.scheme() == "https" => {
+        if !config.inline_remote {
+          log::debug!(
+            "[INLINER] `{}` is a remote URL and config.inline_remote == false",
+            path
+          );
+          continue;
+        }
+        let response = reqwest::blocking::Client::builder()
+          .build()?
+          .get(url.clone())
+          .send()?;
+        if let Some(content_type) = response.headers().get(reqwest::header::CONTENT_TYPE) {
+          let content_type = content_type.to_str().unwrap();
+          if let Some(extension) = extension(path) {
+            let expected_content_type = content_type_map()
+              .get(extension)
+              .map(|c| c.to_string())
+              .unwrap_or_else(|| content_type.to_string());
+            if content_type != expected_content_type {
+              log::debug!(
+                "[INLINER] `{}` response's content type is invalid; expected {} but got {}",
+                path,
+                expected_content_type,
+                content_type,
+              );
+              return Ok(None);
+            }
+          }
+        }
+        // the charset of the response overrides the one of the stylesheet
+        let charset = response
+          .headers()
+          .get(reqwest::header::CONTENT_TYPE)
+          .and_then(|content_type| content_type.to_str().ok())
+          .filter(|content_type| content_type.trim_start().starts_with("text/css"))
+          .and_then(|content_type| {
+            content_type.split(';').skip(1).find_map(|parameter| {
+              let (name, value) = parameter.split_once('=')?;
+              Some(value.trim().trim_matches('"').to_string())
+                .filter(|_| name.trim().eq_ignore_ascii_case("charset"))
+            })
+          });
+        let raw = response.bytes()?.as_ref().to_vec();
+        return Ok(Some(match charset {
+          Some(charset) => js_css::decode_css(&raw, Some(&charset)).into_bytes(),
+          None => raw,
+        }));
+      }
+      _ => {}
     }
+  }
+  if url.is_some() {
+    log::debug!("[INLINER] `{}` was not loaded by any source", path);
+    Ok(None)
   } else {
-    let file_path = PathBuf::from(path);
-    let file_path = if file_path.is_absolute() {
-      file_path
-    } else {
-      root_path.as_ref().to_path_buf().join(file_path)
-    };
+    Err(Error::InvalidPath(path.to_string()))
+  }
+}
+
+fn load_path(
+  ctx: &mut Context,
+  path: &str,
+  integrity: Option<&str>,
+  content_type: Option<&str>,
+) -> Result<Option<String>> {
+  if !ctx.config.inline_fonts && FONT_EXTENSIONS.iter().any(|f| path.ends_with(f)) {
     log::debug!(
-      "[INLINER] loading `{:?}` with fs::read `{:?}`",
-      file_path,
+      "[INLINER] `{}` is a font and config.inline_fonts == false",
       path
     );
-    fs::read(file_path).map(|file| Some(file.to_vec()))?
+    return Ok(None);
+  }
+
+  let raw = load_raw(ctx, path)?;
+  if let (Some(raw), Some(integrity)) = (&raw, integrity) {
+    if !js_css::verify_integrity(ctx, integrity, path, raw) {
+      return Ok(None);
+    }
+  }
+  let raw = match (raw, &ctx.config.asset_transform) {
+    (Some(raw), Some(transform)) => {
+      let info = AssetInfo {
+        path,
+        content_type: asset_content_type(path),
+      };
+      Some((transform.0)(&info, raw))
+    }
+    (raw, _) => raw,
+  };
+  let raw = match raw {
+    Some(raw) if asset_content_type(path) == Some("image/svg+xml") => {
+      Some(svg::inline_references(ctx, path, raw)?)
+    }
+    raw => raw,
+  };
+  let config = &ctx.config;
+  #[cfg(feature = "font-subset")]
+  let raw = match (raw, &config.font_subset) {
+    (Some(raw), Some(subset)) if font::is_sfnt(extension(path)) => {
+      match font::subset(&raw, &ctx.font_characters, subset) {
+        Some(font) => {
+          log::debug!(
+            "[INLINER] subset `{}` from {} to {} bytes",
+            path,
+            raw.len(),
+            font.len()
+          );
+          Some(font)
+        }
+        None => Some(raw),
+      }
+    }
+    (raw, _) => raw,
+  };
+  let (raw, file_extension) = match raw {
+    Some(raw) if config.ico_mode == IcoMode::LargestPng && extension(path) == Some("ico") => {
+      match ico::largest_png(&raw) {
+        Some(png) => (Some(png), Some("png")),
+        None => (Some(raw), Some("ico")),
+      }
+    }
+    #[cfg(feature = "woff2")]
+    Some(raw) if config.convert_fonts_to_woff2 && font::is_sfnt(extension(path)) => {
+      match font::to_woff2(&raw) {
+        Some(woff2) => (Some(woff2), Some("woff2")),
+        None => (Some(raw), extension(path)),
+      }
+    }
+    raw => (raw, extension(path)),
   };
   let res = if let Some(raw) = raw {
     if raw.len() > config.max_inline_size {
@@ -123,10 +1236,36 @@ fn load_path<P: AsRef<Path>>(path: &str, config: &Config, root_path: P) -> Resul
         path
       );
       None
+    } else if let Some(content_type) = content_type.filter(|_| {
+      file_extension
+        .and_then(|extension| content_type_map().get(extension))
+        .is_none()
+    }) {
+      log::debug!(
+        "[INLINER] encoding `{}` as base64 with its declared content type `{}`",
+        path,
+        content_type
+      );
+      Some(format!(
+        "data:{};base64,{}",
+        content_type,
+        encode_base64(&raw)
+      ))
     } else {
-      Some(match path.split('.').last() {
+      Some(match file_extension {
         Some(extension) => {
-          if let Some(content_type) = content_type_map().get(extension) {
+          if content_type_map().get(extension).and_then(|c| c.as_str()) == Some("image/svg+xml")
+            && config.target.supports_utf8_svg()
+          {
+            log::debug!("[INLINER] percent-encoding `{}`", path);
+            format!(
+              "data:image/svg+xml,{}",
+              percent_encoding::utf8_percent_encode(
+                &String::from_utf8_lossy(&raw),
+                SVG_DATA_URI_ESCAPES
+              )
+            )
+          } else if let Some(content_type) = content_type_map().get(extension) {
             log::debug!(
               "[INLINER] encoding `{}` as base64 with content type `{}`",
               path,
@@ -135,13 +1274,15 @@ fn load_path<P: AsRef<Path>>(path: &str, config: &Config, root_path: P) -> Resul
             format!(
               "data:{};base64,{}",
               content_type.as_str().unwrap(),
-              base64::encode(&raw)
+              encode_base64(&raw)
             )
+          } else if extension == "css" {
+            ctx.substitute_defines(js_css::decode_css(&raw, None))
           } else {
-            String::from_utf8_lossy(&raw).to_string()
+            ctx.substitute_defines(String::from_utf8_lossy(&raw).to_string())
           }
         }
-        None => String::from_utf8_lossy(&raw).to_string(),
+        None => ctx.substitute_defines(String::from_utf8_lossy(&raw).to_string()),
       })
     }
   } else {
@@ -150,33 +1291,198 @@ fn load_path<P: AsRef<Path>>(path: &str, config: &Config, root_path: P) -> Resul
   Ok(res)
 }
 
-pub(crate) fn get<P: AsRef<Path>>(
-  cache: &mut HashMap<String, Option<String>>,
+/// The extension of `path`, ignoring its query string.
+fn extension(path: &str) -> Option<&str> {
+  path
+    .split('?')
+    .next()
+    .unwrap_or_default()
+    .split('.')
+    .next_back()
+}
+
+/// The content type of the asset at `path`, based on its extension.
+fn asset_content_type(path: &str) -> Option<&'static str> {
+  let extension = extension(path)?;
+  content_type_map()
+    .get(extension)
+    .and_then(|c| c.as_str())
+    .or(match extension {
+      "css" => Some("text/css"),
+      "js" | "mjs" => Some("text/javascript"),
+      _ => None,
+    })
+}
+
+/// Base64 encoder used for data URIs; public only for the benchmarks.
+#[doc(hidden)]
+#[cfg(not(feature = "simd"))]
+pub fn encode_base64(raw: &[u8]) -> String {
+  base64::encode(raw)
+}
+
+/// Encodes with SIMD instructions when the CPU supports them, falling back to a scalar encoder.
+#[doc(hidden)]
+#[cfg(feature = "simd")]
+pub fn encode_base64(raw: &[u8]) -> String {
+  base64_simd::STANDARD.encode_to_string(raw)
+}
+
+/// Resolves a local reference against `root_path`.
+///
+/// References are percent-decoded first (`my%20logo.png`), falling back to the
+/// raw value for file names that contain a literal `%` sequence.
+fn local_path(root_path: &Path, path: &str) -> PathBuf {
+  let join = |path: &str| {
+    let file_path = PathBuf::from(path);
+    if file_path.is_absolute() {
+      file_path
+    } else {
+      root_path.join(file_path)
+    }
+  };
+  let decoded = join(&percent_encoding::percent_decode_str(path).decode_utf8_lossy());
+  if decoded.exists() {
+    decoded
+  } else {
+    join(path)
+  }
+}
+
+/// Whether an attribute value references nothing, e.g. `src=""`, which would otherwise resolve
+/// to the document's directory.
+fn is_empty_reference(path: &str) -> bool {
+  path.trim().is_empty()
+}
+
+pub(crate) fn get(ctx: &mut Context, path: &str) -> Result<Option<String>> {
+  get_with(ctx, path, None, None)
+}
+
+/// Same as `get`, checking the asset as loaded against the `integrity` metadata of the element
+/// referencing it, see `Config::integrity`.
+pub(crate) fn get_verified(
+  ctx: &mut Context,
   path: &str,
-  config: &Config,
-  root_path: P,
+  integrity: Option<&str>,
+) -> Result<Option<String>> {
+  get_with(ctx, path, integrity, None)
+}
+
+/// Same as `get`, embedding an asset whose extension has no known content type as a data URI of
+/// `content_type` rather than as text.
+pub(crate) fn get_as(ctx: &mut Context, path: &str, content_type: &str) -> Result<Option<String>> {
+  get_with(ctx, path, None, Some(content_type))
+}
+
+fn get_with(
+  ctx: &mut Context,
+  path: &str,
+  integrity: Option<&str>,
+  content_type: Option<&str>,
 ) -> Result<Option<String>> {
   log::debug!("[INLINER] loading {}", path);
-  let query_replacer = regex::Regex::new(r"\??#.*").unwrap();
-  let path = query_replacer.replace_all(path, "").to_string();
   if path.starts_with("data:") {
     return Ok(None);
   }
+  if is_empty_reference(path) {
+    ctx.warn(
+      WarningKind::EmptyReference,
+      "skipped an empty asset reference".into(),
+    );
+    return Ok(None);
+  }
+  let path = &ctx.resolve(path);
+  if ctx.exclude.iter().any(|pattern| pattern.is_match(path)) {
+    log::debug!("[INLINER] `{}` is excluded", path);
+    ctx.record(Asset {
+      path: path.clone(),
+      content_type: asset_content_type(path).map(|c| c.to_string()),
+      size: None,
+      raw_size: None,
+      inlined: false,
+    });
+    ctx.external.insert(ctx.asset_key(path));
+    return Ok(None);
+  }
+  let (path, fragment) = if Url::parse(path).is_err() && local_path(&ctx.root_path, path).is_file()
+  {
+    // a local file name may legitimately contain `#` or `?`
+    (path.to_string(), None)
+  } else {
+    let (path, fragment) = match path.split_once('#') {
+      Some((path, fragment)) => (path, Some(fragment.to_string())),
+      None => (path.as_str(), None),
+    };
+    let path = if ctx.config.query.keeps_query(path) {
+      path.to_string()
+    } else {
+      path.split('?').next().unwrap_or_default().to_string()
+    };
+    (path, fragment)
+  };
 
-  if let Some(res) = cache.get(&path) {
+  ctx.emit(InlineEvent::Loading { path: path.clone() });
+  // a cached asset was only checked against the metadata it was loaded with
+  let integrity = integrity.filter(|_| ctx.config.integrity != IntegrityPolicy::Ignore);
+  let verified_key = integrity.map(|integrity| format!("{} {}", ctx.cache_key(&path), integrity));
+  // the same asset may be embedded as text and as a data URI of its declared content type
+  let cache_path = match content_type {
+    Some(content_type) => format!("{} {}", path, content_type),
+    None => path.clone(),
+  };
+  let cached = match &verified_key {
+    Some(key) if !ctx.verified.contains(key) => None,
+    _ => ctx.cached(&cache_path),
+  };
+  let res = if let Some(res) = cached {
     log::debug!("[INLINER] hit cache on {}", path);
-    Ok(res.clone())
+    res
   } else {
-    match load_path(&path, config, root_path) {
+    match load_path(ctx, &path, integrity, content_type) {
       Ok(res) => {
-        cache.insert(path, res.clone());
-        Ok(res)
+        // an asset rejected by its integrity metadata may still be inlined by another reference
+        if integrity.is_none() || res.is_some() {
+          ctx.store(&cache_path, res.clone());
+        }
+        if let (Some(key), Some(_)) = (verified_key, &res) {
+          ctx.verified.insert(key);
+        }
+        res
       }
       Err(e) => {
         log::error!("error loading {}: {:?}", path, e);
-        Ok(None)
+        None
       }
     }
+  };
+  // the fragment selects a part of the file (`icons.svg#gradient`), which its data URI keeps
+  let res = match (res, fragment) {
+    (Some(content), Some(fragment)) if content.starts_with("data:") => {
+      Some(format!("{}#{}", content, fragment))
+    }
+    (res, _) => res,
+  };
+
+  let inlined = match &res {
+    Some(content) => ctx.reserve_output(&path, content.len())?,
+    None => false,
+  };
+  ctx.record(Asset {
+    path: path.clone(),
+    content_type: asset_content_type(&path).map(|c| c.to_string()),
+    size: res.as_ref().map(|content| content.len()),
+    raw_size: res.as_deref().map(raw_size),
+    inlined,
+  });
+
+  let key = ctx.asset_key(&path);
+  if inlined {
+    ctx.inlined.insert(key);
+    Ok(res)
+  } else {
+    ctx.external.insert(key);
+    Ok(None)
   }
 }
 
@@ -186,8 +1492,16 @@ pub(crate) fn get<P: AsRef<Path>>(
 /// * `file_path` - The path of the html file.
 /// * `config` - Pass a config file to select what features to enable. Use `Default::default()` to enable everything
 pub fn inline_file<P: AsRef<Path>>(file_path: P, config: Config) -> Result<String> {
+  inline_file_with_report(file_path, config).map(|(html, _)| html)
+}
+
+/// Same as `inline_file()`, also returning the `Report` of the run.
+pub fn inline_file_with_report<P: AsRef<Path>>(
+  file_path: P,
+  config: Config,
+) -> Result<(String, Report)> {
   let html = fs::read_to_string(&file_path)?;
-  inline_html_string(&html, &file_path.as_ref().parent().unwrap(), config)
+  inline_html_string_with_report(&html, file_path.as_ref().parent().unwrap(), config)
 }
 
 /// Returns a `Result<String>` with all the assets linked in the the html string inlined.
@@ -197,23 +1511,220 @@ pub fn inline_file<P: AsRef<Path>>(file_path: P, config: Config) -> Result<Strin
 /// * `root_path` - The root all relative paths in the html will be evaluated with, usually this is the folder the html file is in.
 /// * `config` - Pass a config file to select what features to enable. Use `Default::default()` to enable everything
 ///
+/// Inlining the output again with the same config leaves it as is, besides the references left
+/// external by the first run, e.g. the `@import` rules over `Config::max_import_depth`.
 pub fn inline_html_string<P: AsRef<Path>>(
   html: &str,
   root_path: P,
   config: Config,
 ) -> Result<String> {
-  let mut cache = HashMap::new();
-  let root_path = root_path.as_ref().canonicalize().unwrap();
+  inline_html_string_with_report(html, root_path, config).map(|(html, _)| html)
+}
+
+/// Same as `inline_html_string()`, also returning the `Report` of the run.
+pub fn inline_html_string_with_report<P: AsRef<Path>>(
+  html: &str,
+  root_path: P,
+  config: Config,
+) -> Result<(String, Report)> {
+  let (document, report) = process(html, root_path.as_ref(), config, None, false)?;
+  Ok((document.to_string(), report))
+}
+
+/// Resolves every asset the html file at file path references without producing any output or
+/// file (see `OversizedUrls::CopyTo`), returning the `Report` an `inline_file()` run would
+/// produce; useful for size budget checks.
+pub fn analyze<P: AsRef<Path>>(file_path: P, config: Config) -> Result<Report> {
+  let html = fs::read_to_string(&file_path)?;
+  analyze_html_string(&html, file_path.as_ref().parent().unwrap(), config)
+}
+
+/// Same as `analyze()` for an html string, see `inline_html_string()`.
+pub fn analyze_html_string<P: AsRef<Path>>(
+  html: &str,
+  root_path: P,
+  config: Config,
+) -> Result<Report> {
+  process(html, root_path.as_ref(), config, None, true).map(|(_, report)| report)
+}
+
+/// Same as `inline_html_string()`, but only the elements matching the CSS `selector`
+/// (e.g. `head` or `#widget`) are processed; the rest of the document is left untouched.
+pub fn inline_subtree<P: AsRef<Path>>(
+  html: &str,
+  root_path: P,
+  selector: &str,
+  config: Config,
+) -> Result<String> {
+  let (document, _) = process(html, root_path.as_ref(), config, Some(selector), false)?;
+  Ok(document.to_string())
+}
+
+/// Runs every pass on the parsed `html`, or only on the subtrees matching `selector`, without
+/// side effects if `dry_run` is set.
+fn process(
+  html: &str,
+  root_path: &Path,
+  config: Config,
+  selector: Option<&str>,
+  dry_run: bool,
+) -> Result<(kuchiki::NodeRef, Report)> {
+  let root_path = root_path.canonicalize().unwrap();
+  let mut ctx = Context::new(config, root_path, html);
+  ctx.dry_run = dry_run;
   let document = kuchiki::parse_html().one(html);
+  #[cfg(feature = "font-subset")]
+  {
+    ctx.font_characters = font::document_characters(&document);
+  }
 
-  binary::inline_base64(&mut cache, &config, &root_path, &document)?;
-  js_css::inline_script_link(&mut cache, &config, &root_path, &document)?;
+  let roots = match selector {
+    Some(selector) => {
+      let matches: Vec<kuchiki::NodeRef> = document
+        .select(selector)
+        .map_err(|()| Error::InvalidSelector(selector.to_string()))?
+        .map(|element| element.as_node().clone())
+        .collect();
+      // a subtree nested in another matched subtree is processed with it
+      matches
+        .iter()
+        .filter(|node| !node.ancestors().any(|ancestor| matches.contains(&ancestor)))
+        .cloned()
+        .collect()
+    }
+    None => vec![document.clone()],
+  };
 
-  let html = document.to_string();
-  let whitespace_regex = regex::Regex::new(r"( {2,})").unwrap();
-  let html = whitespace_regex.replace_all(&html, " ").to_string();
+  run_passes(&mut ctx, &document, &roots, selector.is_none())?;
+  ctx.report.document_size = html.len();
+  Ok((document, ctx.report))
+}
+
+/// Runs every pass on the `roots` of `document`. The passes acting on the whole document only
+/// run if `whole_document` is set.
+fn run_passes(
+  ctx: &mut Context,
+  document: &kuchiki::NodeRef,
+  roots: &[kuchiki::NodeRef],
+  whole_document: bool,
+) -> Result<()> {
+  html::apply_base(ctx, document);
+  ctx.csp_nonces = html::uses_csp_nonces(document);
+  for root in roots {
+    inline_assets(ctx, root)?;
+  }
+  if whole_document {
+    html::inline_preloads(ctx, document)?;
+    html::remove_resource_hints(ctx, document);
+    html::remove_base(ctx, document);
+    js_css::dedupe_font_faces(document);
+    js_css::purge_unused_css(ctx, document);
+    js_css::merge_styles(ctx, document);
+    js_css::extract_critical_css(ctx, document);
+  }
+  html::apply_csp_nonce(ctx, document, roots);
+  for root in roots {
+    html::check_mixed_content(ctx, root);
+    html::apply_comment_policy(ctx, root);
+    html::minify_whitespace(ctx, root);
+  }
+  Ok(())
+}
+
+/// Runs the passes inlining the assets referenced under `root`.
+pub(crate) fn inline_assets(ctx: &mut Context, root: &kuchiki::NodeRef) -> Result<()> {
+  html::expand_templates(root);
+  html::inline_iframes(ctx, root)?;
+  html::inline_noscript(ctx, root)?;
+  html::inline_conditional_comments(ctx, root)?;
+  svg::inline_images(ctx, root)?;
+  binary::select_media_sources(ctx, root)?;
+  binary::inline_base64(ctx, root)?;
+  binary::inline_lazy_attributes(ctx, root)?;
+  binary::inline_extra_targets(ctx, root)?;
+  binary::inline_downloads(ctx, root)?;
+  manifest::inline_manifests(ctx, root)?;
+  svg::inline_sprites(ctx, root)?;
+  modules::bundle_modules(ctx, root)?;
+  js_css::inline_script_link(ctx, root)?;
+  js_css::inline_workers(ctx, root)?;
+  js_css::inline_wasm(ctx, root)?;
+  js_css::inline_script_assets(ctx, root)?;
+  js_css::apply_service_worker_policy(ctx, root);
+  Ok(())
+}
+
+/// Loads the text of a document that is processed rather than embedded (iframes, SVG sprites);
+/// `None` if it's excluded or can't be loaded.
+pub(crate) fn load_text(ctx: &Context, path: &str) -> Option<String> {
+  if ctx.exclude.iter().any(|pattern| pattern.is_match(path)) {
+    log::debug!("[INLINER] `{}` is excluded", path);
+    return None;
+  }
+  match load_raw(ctx, path) {
+    Ok(raw) => raw.map(|raw| String::from_utf8_lossy(&raw).to_string()),
+    Err(e) => {
+      log::error!("error loading {}: {:?}", path, e);
+      None
+    }
+  }
+}
+
+/// Inlines the document at `path` (an iframe's `src`) with its own context sharing the cache
+/// of `ctx`, returning the output HTML.
+pub(crate) fn inline_nested_document(ctx: &mut Context, path: &str) -> Result<Option<String>> {
+  if is_empty_reference(path) {
+    ctx.warn(
+      WarningKind::EmptyReference,
+      "skipped an empty iframe source".into(),
+    );
+    return Ok(None);
+  }
+  let path = &ctx.resolve(path);
+  let html = match load_text(ctx, path) {
+    Some(html) => html,
+    None => return Ok(None),
+  };
+
+  let (root_path, base_url) = match Url::parse(path) {
+    Ok(url) => (ctx.root_path.clone(), Some(url)),
+    Err(_) => {
+      let file_path = local_path(&ctx.root_path, path);
+      (file_path.parent().unwrap().to_path_buf(), None)
+    }
+  };
+  let html_len = html.len();
+  let mut nested = Context::new(ctx.config.clone(), root_path, &html);
+  nested.base_url = base_url;
+  nested.iframe_depth = ctx.iframe_depth + 1;
+  nested.dry_run = ctx.dry_run;
+  nested.cache = std::mem::take(&mut ctx.cache);
+  let document = kuchiki::parse_html().one(html);
+  #[cfg(feature = "font-subset")]
+  {
+    nested.font_characters = font::document_characters(&document);
+  }
+  let res = run_passes(
+    &mut nested,
+    &document,
+    std::slice::from_ref(&document),
+    true,
+  );
+  ctx.cache = std::mem::take(&mut nested.cache);
+  ctx.report.warnings.append(&mut nested.report.warnings);
+  ctx.report.assets.append(&mut nested.report.assets);
+  res?;
 
-  Ok(html)
+  let srcdoc = document.to_string();
+  let inlined = ctx.reserve_output(path, srcdoc.len())?;
+  ctx.record(Asset {
+    path: path.clone(),
+    content_type: Some("text/html".into()),
+    size: Some(srcdoc.len()),
+    raw_size: Some(html_len),
+    inlined,
+  });
+  Ok(Some(srcdoc).filter(|_| inlined))
 }
 
 #[cfg(test)]
@@ -222,12 +1733,848 @@ mod tests {
   use std::{
     fs::{read, read_to_string},
     io::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
     thread::spawn,
   };
   use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
   use tiny_http::{Header, Response, Server, StatusCode};
 
+  #[cfg(feature = "font-subset")]
+  use super::FontSubset;
+  use super::{
+    AssetCache, AssetResolver, AssetTransform, CommentPolicy, ConditionalComments, Config,
+    CspNonce, DeferredScripts, DeviceProfile, DuplicateScripts, Error, EventHook, ExcludePattern,
+    IcoMode, IconPolicy, InlineEvent, IntegrityPolicy, MediaSources, NomodulePolicy,
+    OutputSizePolicy, OversizedUrls, PreloadPolicy, QueryPolicy, Resolver, ServiceWorkers, Source,
+    SourceMapPolicy, SvgMode, SvgSprites, Target, TransformLevel, WarningKind,
+  };
+
+  /// The lazy-loading attributes of the `lazy-attributes` fixtures.
+  fn lazy_attributes() -> Vec<String> {
+    vec![
+      "data-src".into(),
+      "data-srcset".into(),
+      "data-background".into(),
+    ]
+  }
+
+  /// The config a fixture is inlined with; defaults to `Config::default()`.
+  fn fixture_config(name: &str) -> Config {
+    match name {
+      "output-size-budget" => Config {
+        max_output_size: Some(300),
+        ..Default::default()
+      },
+      "import-depth" => Config {
+        max_import_depth: 1,
+        ..Default::default()
+      },
+      "import-hoist" => Config {
+        max_import_depth: 1,
+        ..Default::default()
+      },
+      "comments-strip" => Config {
+        comments: CommentPolicy::Strip,
+        ..Default::default()
+      },
+      "comments-conditional" => Config {
+        comments: CommentPolicy::StripExceptConditional,
+        ..Default::default()
+      },
+      "iframe" => Config {
+        max_iframe_depth: 2,
+        ..Default::default()
+      },
+      "empty-src" => Config {
+        max_iframe_depth: 1,
+        ..Default::default()
+      },
+      "svg-inline-element" => Config {
+        svg_mode: SvgMode::InlineElement,
+        ..Default::default()
+      },
+      "social-images" => Config {
+        inline_social_images: true,
+        ..Default::default()
+      },
+      "lazy-attributes" => Config {
+        lazy_attributes: lazy_attributes(),
+        ..Default::default()
+      },
+      "lazy-attributes-promote" => Config {
+        lazy_attributes: lazy_attributes(),
+        promote_lazy_attributes: true,
+        ..Default::default()
+      },
+      "media-sources-first" => Config {
+        media_sources: MediaSources::First,
+        ..Default::default()
+      },
+      "media-sources-collapse" => Config {
+        media_sources: MediaSources::Collapse,
+        ..Default::default()
+      },
+      "conditional-comments" => Config {
+        conditional_comments: ConditionalComments::Inline,
+        ..Default::default()
+      },
+      "conditional-comments-strip" => Config {
+        conditional_comments: ConditionalComments::Strip,
+        ..Default::default()
+      },
+      "download" => Config {
+        max_download_size: Some(1000),
+        ..Default::default()
+      },
+      "apple-touch-startup-image-devices" => Config {
+        startup_image_devices: Some(vec![
+          DeviceProfile {
+            width: 375,
+            height: 667,
+            pixel_ratio: 2.0,
+          },
+          DeviceProfile {
+            width: 1024,
+            height: 1366,
+            pixel_ratio: 2.0,
+          },
+        ]),
+        ..Default::default()
+      },
+      "extra-targets" => Config {
+        extra_targets: vec![
+          ("app-avatar".into(), "image".into()),
+          ("ion-img".into(), "src".into()),
+          ("app-picture".into(), "image-srcset".into()),
+        ],
+        ..Default::default()
+      },
+      "ico-largest-png" => Config {
+        ico_mode: IcoMode::LargestPng,
+        ..Default::default()
+      },
+      "preload-keep" => Config {
+        preloads: PreloadPolicy::Keep,
+        ..Default::default()
+      },
+      "critical-css" => Config {
+        critical_css: true,
+        ..Default::default()
+      },
+      "inline-workers" => Config {
+        inline_workers: true,
+        ..Default::default()
+      },
+      "service-workers-remove" => Config {
+        service_workers: ServiceWorkers::Remove,
+        ..Default::default()
+      },
+      "css-source-map-keep" => Config {
+        source_maps: SourceMapPolicy::Keep,
+        ..Default::default()
+      },
+      "css-source-map-inline" => Config {
+        source_maps: SourceMapPolicy::Inline,
+        ..Default::default()
+      },
+      "script-source-map-inline" => Config {
+        source_maps: SourceMapPolicy::Inline,
+        ..Default::default()
+      },
+      "csp-nonce" => Config {
+        critical_css: true,
+        ..Default::default()
+      },
+      "csp-nonce-random" => Config {
+        csp_nonce: CspNonce::Random,
+        critical_css: true,
+        seed: Some(7),
+        ..Default::default()
+      },
+      "module-bundle" => Config {
+        bundle_modules: true,
+        ..Default::default()
+      },
+      "inline-wasm" => Config {
+        inline_wasm: true,
+        ..Default::default()
+      },
+      "script-concat" => Config {
+        concat_scripts: true,
+        annotate_sources: true,
+        ..Default::default()
+      },
+      "script-defer-order" => Config {
+        deferred_scripts: DeferredScripts::DataUri,
+        ..Default::default()
+      },
+      "script-assets" => Config {
+        script_asset_patterns: vec![regex::Regex::new(r"\.gif$").unwrap()],
+        ..Default::default()
+      },
+      "module-import-map" => Config {
+        bundle_modules: true,
+        remove_import_map: true,
+        ..Default::default()
+      },
+      "module-dynamic-import" => Config {
+        bundle_modules: true,
+        bundle_dynamic_imports: true,
+        ..Default::default()
+      },
+      "oversized-urls" => Config {
+        max_inline_size: 50,
+        oversized_urls: OversizedUrls::Prefix("https://cdn.example.com/app/".into()),
+        ..Default::default()
+      },
+      "merge-styles" => Config {
+        merge_styles: true,
+        ..Default::default()
+      },
+      "purge-css" => Config {
+        purge_css: true,
+        purge_safelist: vec![regex::Regex::new(r"\.modal").unwrap()],
+        ..Default::default()
+      },
+      "svg-sprites-replace" => Config {
+        svg_sprites: SvgSprites::Replace,
+        ..Default::default()
+      },
+      "transform-none" => Config {
+        transform_level: TransformLevel::None,
+        ..Default::default()
+      },
+      "whitespace-aggressive" => Config {
+        transform_level: TransformLevel::Aggressive,
+        ..Default::default()
+      },
+      "whitespace-aggressive-strings" => Config {
+        transform_level: TransformLevel::Aggressive,
+        ..Default::default()
+      },
+      "script-duplicate-remove" => Config {
+        duplicate_scripts: DuplicateScripts::Remove,
+        ..Default::default()
+      },
+      "script-duplicate-comment" => Config {
+        duplicate_scripts: DuplicateScripts::Comment,
+        ..Default::default()
+      },
+      "annotate-sources" => Config {
+        annotate_sources: true,
+        ..Default::default()
+      },
+      "nomodule-remove" => Config {
+        nomodule: NomodulePolicy::Remove,
+        ..Default::default()
+      },
+      "base-path" => Config {
+        remove_base: true,
+        ..Default::default()
+      },
+      "mixed-content-upgrade" => Config {
+        upgrade_insecure_requests: true,
+        ..Default::default()
+      },
+      "target-modern" => Config {
+        target: Target::Modern,
+        ..Default::default()
+      },
+      "exclude" => Config {
+        exclude: vec![
+          ExcludePattern::Glob("*.min.js".into()),
+          ExcludePattern::Regex(regex::Regex::new(r"^http://localhost:54321/").unwrap()),
+        ],
+        ..Default::default()
+      },
+      "resource-hints" => Config {
+        remove_resource_hints: true,
+        ..Default::default()
+      },
+      "defines" => Config {
+        defines: vec![
+          ("__APP_VERSION__", "\"1.2.3\""),
+          ("__APP_VERSION_FULL__", "\"1.2.3-beta.1\""),
+          ("import.meta.env.ACCENT", "#ff0000"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect(),
+        ..Default::default()
+      },
+      "icon-links" => Config {
+        icon_links: vec![
+          ("apple-touch-icon", IconPolicy::Remove),
+          ("apple-touch-startup-image", IconPolicy::Remove),
+          ("shortcut icon", IconPolicy::Keep),
+        ]
+        .into_iter()
+        .map(|(rel, policy)| (rel.to_string(), policy))
+        .collect(),
+        ..Default::default()
+      },
+      "asset-transform" => Config {
+        asset_transform: Some(AssetTransform::new(|asset, bytes| {
+          if asset.content_type == Some("text/javascript") {
+            String::from_utf8(bytes)
+              .unwrap()
+              .replace("Hello world", "Hello inliner")
+              .into_bytes()
+          } else {
+            bytes
+          }
+        })),
+        ..Default::default()
+      },
+      "resolver" => Config {
+        resolver: Some(AssetResolver::new(EmbeddedAssets)),
+        ..Default::default()
+      },
+      "resolver-sources" => Config {
+        resolver: Some(AssetResolver::new(EmbeddedAssets)),
+        sources: vec![Source::Filesystem, Source::Resolver],
+        ..Default::default()
+      },
+      _ => Default::default(),
+    }
+  }
+
+  /// Serves a few assets from memory, like an app embedding its assets would.
+  struct EmbeddedAssets;
+
+  impl Resolver for EmbeddedAssets {
+    fn resolve(&self, path: &str) -> crate::Result<Option<Vec<u8>>> {
+      Ok(match path {
+        "embedded.js" => Some(b"console.log('embedded')".to_vec()),
+        "script.js" => Some(b"console.log('embedded script.js')".to_vec()),
+        "http://localhost:54321/remote.js" => Some(b"console.log('embedded remote')".to_vec()),
+        _ => None,
+      })
+    }
+  }
+
+  #[test]
+  fn strict_output_size() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures");
+    let config = Config {
+      max_output_size: Some(300),
+      output_size_policy: OutputSizePolicy::Strict,
+      ..Default::default()
+    };
+    match super::inline_file(root.join("output-size-budget.src.html"), config) {
+      Err(Error::OutputSizeExceeded(path)) => assert!(path.ends_with("colour.png")),
+      other => panic!("expected OutputSizeExceeded, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn report_warnings() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures");
+    let config = Config {
+      max_output_size: Some(300),
+      nomodule: NomodulePolicy::Remove,
+      ..Default::default()
+    };
+    let (_, report) =
+      super::inline_file_with_report(root.join("output-size-budget.src.html"), config.clone())
+        .unwrap();
+    assert!(report.has_warning(WarningKind::OutputSizeExceeded));
+    assert!(!report.has_warning(WarningKind::NomoduleRemoved));
+
+    let (_, report) =
+      super::inline_file_with_report(root.join("nomodule-remove.src.html"), config).unwrap();
+    assert_eq!(report.warnings.len(), 1);
+    assert_eq!(report.warnings[0].kind, WarningKind::NomoduleRemoved);
+
+    let (_, report) =
+      super::inline_file_with_report(root.join("mixed-content.src.html"), Config::default())
+        .unwrap();
+    assert_eq!(report.warnings.len(), 4);
+    assert!(report
+      .warnings
+      .iter()
+      .all(|warning| warning.kind == WarningKind::MixedContent));
+
+    let (_, report) =
+      super::inline_file_with_report(root.join("empty-src.src.html"), fixture_config("empty-src"))
+        .unwrap();
+    assert_eq!(report.warnings.len(), 6);
+    assert!(report
+      .warnings
+      .iter()
+      .all(|warning| warning.kind == WarningKind::EmptyReference));
+
+    let (_, report) =
+      super::inline_file_with_report(root.join("import-cycle.src.html"), Config::default())
+        .unwrap();
+    assert_eq!(report.warnings.len(), 2);
+    assert!(report
+      .warnings
+      .iter()
+      .all(|warning| warning.kind == WarningKind::CircularImport));
+
+    let config = Config {
+      service_workers: ServiceWorkers::Warn,
+      ..Default::default()
+    };
+    let (output, report) =
+      super::inline_file_with_report(root.join("service-workers-remove.src.html"), config).unwrap();
+    assert!(output.contains("navigator.serviceWorker.register('/sw.js', { scope: '/' })"));
+    assert_eq!(report.warnings.len(), 1);
+    assert_eq!(report.warnings[0].kind, WarningKind::ServiceWorker);
+
+    let config = Config {
+      integrity: IntegrityPolicy::Warn,
+      ..Default::default()
+    };
+    let (output, report) =
+      super::inline_file_with_report(root.join("integrity.src.html"), config).unwrap();
+    assert!(!output.contains("integrity"));
+    assert_eq!(report.warnings.len(), 2);
+    assert!(report
+      .warnings
+      .iter()
+      .all(|warning| warning.kind == WarningKind::IntegrityMismatch));
+  }
+
+  #[test]
+  fn analyze() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures");
+    let config = Config {
+      max_output_size: Some(300),
+      ..Default::default()
+    };
+    let report = super::analyze(root.join("output-size-budget.src.html"), config).unwrap();
+    assert_eq!(report.assets.len(), 2);
+    let gif = &report.assets[0];
+    assert!(gif.inlined);
+    assert_eq!(gif.content_type.as_deref(), Some("image/gif"));
+    assert_eq!(report.inlined_size(), gif.size.unwrap());
+    let png = &report.assets[1];
+    assert!(!png.inlined);
+    assert!(png.size.is_some());
+    assert!(report.has_warning(WarningKind::OutputSizeExceeded));
+  }
+
+  #[test]
+  fn analyze_copy_to() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures");
+    let directory = std::env::temp_dir().join("inliner-analyze-copy-to");
+    let _ = std::fs::remove_dir_all(&directory);
+    let config = Config {
+      max_inline_size: 10,
+      oversized_urls: OversizedUrls::CopyTo(directory.clone()),
+      ..Default::default()
+    };
+    let html = r#"<style>p { background: url(colour.png); }</style>"#;
+    let report = super::analyze_html_string(html, &root, config).unwrap();
+    assert!(!report.assets[0].inlined);
+    assert!(!directory.exists());
+  }
+
+  #[test]
+  fn inline_subtree() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures");
+    let html = r#"<html><head><script src="script.min.js"></script></head>
+<body><img src="1x1.gif"><div id="widget"><img src="1x1.gif"></div></body></html>"#;
+    let output = super::inline_subtree(html, &root, "#widget", Config::default()).unwrap();
+    assert!(output.contains(r#"<script src="script.min.js"></script>"#));
+    assert!(output.contains(r#"<body><img src="1x1.gif"><div id="widget"><img src="data:"#));
+
+    match super::inline_subtree(html, &root, "#[", Config::default()) {
+      Err(Error::InvalidSelector(selector)) => assert_eq!(selector, "#["),
+      other => panic!("expected InvalidSelector, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn shared_cache() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures");
+    let html = r#"<img src="colour.png">"#;
+    let cache = AssetCache::new();
+    let config = Config {
+      cache: Some(cache.clone()),
+      ..Default::default()
+    };
+    let small = Config {
+      max_inline_size: 10,
+      ..config.clone()
+    };
+
+    // a run with a lower size limit must not reuse the data URI cached by the first run
+    let output = super::inline_html_string(html, &root, config.clone()).unwrap();
+    assert!(output.contains("data:image/png"));
+    let output = super::inline_html_string(html, &root, small.clone()).unwrap();
+    assert!(output.contains(r#"<img src="colour.png">"#));
+    // and the other way around
+    cache.clear();
+    let output = super::inline_html_string(html, &root, small).unwrap();
+    assert!(output.contains(r#"<img src="colour.png">"#));
+    let output = super::inline_html_string(html, &root, config).unwrap();
+    assert!(output.contains("data:image/png"));
+  }
+
+  /// Records the paths it is asked to load.
+  struct CountingResolver(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+  impl Resolver for CountingResolver {
+    fn resolve(&self, path: &str) -> crate::Result<Option<Vec<u8>>> {
+      self.0.lock().unwrap().push(path.to_string());
+      Ok(Some(b"console.log('counted')".to_vec()))
+    }
+  }
+
+  #[test]
+  fn integrity_loads() {
+    use sha2::Digest;
+
+    let loads = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let config = Config {
+      resolver: Some(AssetResolver::new(CountingResolver(loads.clone()))),
+      sources: vec![Source::Resolver],
+      exclude: vec![ExcludePattern::Glob("excluded.js".into())],
+      ..Default::default()
+    };
+    let hash = super::encode_base64(&sha2::Sha256::digest(b"console.log('counted')"));
+    let html = format!(
+      r#"<script src="counted.js" integrity="sha256-{0}"></script><script src="excluded.js" integrity="sha256-{0}"></script>"#,
+      hash
+    );
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures");
+    let output = super::inline_html_string(&html, &root, config).unwrap();
+    assert!(output.contains("<script>console.log('counted')</script>"));
+    assert!(output.contains(r#"src="excluded.js""#));
+    // the checked bytes are the inlined ones, and the excluded asset is never loaded
+    assert_eq!(*loads.lock().unwrap(), vec!["counted.js".to_string()]);
+  }
+
+  #[test]
+  fn events() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures");
+    let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorded = events.clone();
+    let config = Config {
+      on_event: Some(EventHook::new(move |event| {
+        recorded.lock().unwrap().push(event.clone())
+      })),
+      ..Default::default()
+    };
+    let (_, report) =
+      super::inline_file_with_report(root.join("output-size-budget.src.html"), config).unwrap();
+
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 4);
+    assert_eq!(
+      events[0],
+      InlineEvent::Loading {
+        path: "1x1.gif".into()
+      }
+    );
+    assert_eq!(events[1], InlineEvent::Resolved(report.assets[0].clone()));
+  }
+
+  #[cfg(feature = "async")]
+  #[test]
+  fn event_stream() {
+    use futures_util::StreamExt;
+
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures");
+    let (events, output) =
+      super::inline_file_stream(root.join("output-size-budget.src.html"), Config::default());
+    let events: Vec<InlineEvent> = futures_executor::block_on(events.collect());
+    assert_eq!(events.len(), 4);
+    assert!(futures_executor::block_on(output)
+      .unwrap()
+      .contains("data:image/png"));
+  }
+
+  #[cfg(feature = "font-subset")]
+  #[test]
+  fn font_subset() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures");
+    let html = r#"<style>@font-face{font-family:Tiny;src:url(fonts/tiny.ttf)}</style><p>AB</p>"#;
+    let glyphs = |config| {
+      let output = super::inline_html_string(html, &root, config).unwrap();
+      let data = output.split("base64,").nth(1).unwrap();
+      let font = base64::decode(data.split(&['\'', ')'][..]).next().unwrap()).unwrap();
+      let face = ttf_parser::Face::parse(&font, 0).unwrap();
+      ['A', 'B', 'C']
+        .iter()
+        .map(|c| {
+          face
+            .glyph_bounding_box(face.glyph_index(*c).unwrap())
+            .is_some()
+        })
+        .collect::<Vec<_>>()
+    };
+
+    assert_eq!(glyphs(Config::default()), [true, true, true]);
+    let config = Config {
+      font_subset: Some(FontSubset::default()),
+      ..Default::default()
+    };
+    assert_eq!(glyphs(config), [true, true, false]);
+    let config = Config {
+      font_subset: Some(FontSubset {
+        unicode_ranges: vec![0x43..=0x43],
+      }),
+      ..Default::default()
+    };
+    assert_eq!(glyphs(config), [true, true, true]);
+  }
+
+  #[cfg(feature = "woff2")]
+  #[test]
+  fn woff2_fonts() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures");
+    let html =
+      r#"<style>@font-face{font-family:Tiny;src:url(fonts/tiny.ttf) format("truetype")}</style>"#;
+    let config = Config {
+      convert_fonts_to_woff2: true,
+      ..Default::default()
+    };
+    let output = super::inline_html_string(html, &root, config).unwrap();
+    let data = output.split("data:font/woff2;base64,").nth(1).unwrap();
+    let (data, rest) = data.split_once(&['\'', ')'][..]).unwrap();
+    assert!(rest
+      .trim_start_matches(&['\'', ')', ' '][..])
+      .starts_with(r#"format("woff2")"#));
+
+    let be = |bytes: &[u8], offset: usize| {
+      u32::from_be_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+      ])
+    };
+    let woff2 = base64::decode(data).unwrap();
+    assert_eq!(&woff2[..4], b"wOF2");
+    assert_eq!(be(&woff2, 8) as usize, woff2.len());
+    // the decompressed tables are the ones of the font, in the order of the directory
+    let ttf = read(root.join("fonts/tiny.ttf")).unwrap();
+    let mut tables: Vec<&[u8]> = (0..ttf[5] as usize)
+      .map(|i| {
+        let offset = be(&ttf, 12 + i * 16 + 8) as usize;
+        &ttf[offset..offset + be(&ttf, 12 + i * 16 + 12) as usize]
+      })
+      .collect();
+    // cmap, glyf, head, hhea, hmtx, loca...: loca moves after glyf
+    let loca = tables.remove(5);
+    tables.insert(2, loca);
+    let directory_len = 2 * tables.len();
+    let mut stream = Vec::new();
+    brotli::BrotliDecompress(&mut &woff2[48 + directory_len..], &mut stream).unwrap();
+    assert_eq!(stream, tables.concat());
+  }
+
+  #[cfg(feature = "css-minify")]
+  #[test]
+  fn css_minify() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures");
+    let html = r#"<style>p { content: "a  b; c"; width: calc( 100% - 2px ); background: url(1x1.gif); }</style><p style="color: #ff0000 ; margin : 0px">"#;
+    let output = super::inline_html_string(html, &root, Config::default()).unwrap();
+    assert!(output.contains(
+      r#"<style>p{content:"a  b; c";background:url(data:image/gif;base64,R0lGODlhAQABAIABAP///wAAACwAAAAAAQABAAACAkQBADs=);width:calc(100% - 2px)}</style>"#
+    ));
+    assert!(output.contains(r#"<p style="color:red;margin:0">"#));
+
+    let html = r#"<style>.card { & img { background: url(1x1.gif); } @media (min-width: 600px) { & > .icon { color: red; } } }</style>"#;
+    let output = super::inline_html_string(html, &root, Config::default()).unwrap();
+    assert!(output.contains(
+      r#"<style>.card{& img{background:url(data:image/gif;base64,R0lGODlhAQABAIABAP///wAAACwAAAAAAQABAAACAkQBADs=)}@media (width>=600px){&>.icon{color:red}}}</style>"#
+    ));
+  }
+
+  #[cfg(feature = "js-minify")]
+  #[test]
+  fn js_minify() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures");
+    let script = "/*! keep */\n// a comment\nlet a = 1\nlet b = a / 2 / 1;\nconst re = /[/]  x/g;\nconst s = `${ a }  ${ `x  y` }`;\nif (a)\n{\n  a ++\n  + b;\n}\n";
+    assert_eq!(
+      super::js_minify::minify(script),
+      "/*! keep */\nlet a=1\nlet b=a/2/1;const re=/[/]  x/g;const s=`${ a }  ${ `x  y` }`;if(a)\n{a++\n+b;}"
+    );
+
+    // a `/` after `)`, `]` or `}` may start a regular expression: the rest of the line from it is
+    // kept as is
+    let script = "if (a)  /x  y/.test(s)\nlet c = (a  +  b)  /  2\nlet d = 1  +  2;\nf(x) / `a\n  b`;\nlet e  =  1\n";
+    assert_eq!(
+      super::js_minify::minify(script),
+      "if(a)/x  y/.test(s)\nlet c=(a+b)/  2\nlet d=1+2;f(x)/ `a\n  b`;\nlet e  =  1\n"
+    );
+
+    // a `/` after a postfix increment or a property named like a keyword is a division
+    assert_eq!(
+      super::js_minify::minify("x = i++ / 2; s = \"a/b  c\";"),
+      "x=i++/2;s=\"a/b  c\";"
+    );
+    assert_eq!(
+      super::js_minify::minify("x = a[0]-- / 2; y = a.in / 2; z = b?.of / 2; s = \"1/2  3\""),
+      "x=a[0]--/2;y=a.in/2;z=b?.of/2;s=\"1/2  3\""
+    );
+    assert_eq!(
+      super::js_minify::minify("a = b + ++c - -d - --e;"),
+      "a=b+ ++c- -d- --e;"
+    );
+
+    let html = r#"<script src="script-local.js"></script>"#;
+    let output = super::inline_html_string(html, &root, Config::default()).unwrap();
+    assert!(output.contains("var array=[4,8,15,16,23,42];for(var i=0;i<array.length;i++){"));
+  }
+
+  #[test]
+  fn malformed_ico() {
+    // an ICO of a single 32-bit bitmap, whose DIB header has the given dimensions
+    let ico = |width: u32, height: u32| {
+      let mut ico = vec![0, 0, 1, 0, 1, 0];
+      ico.extend_from_slice(&[1, 1, 0, 0, 1, 0, 32, 0]);
+      ico.extend_from_slice(&44u32.to_le_bytes());
+      ico.extend_from_slice(&22u32.to_le_bytes());
+      ico.extend_from_slice(&40u32.to_le_bytes());
+      ico.extend_from_slice(&width.to_le_bytes());
+      ico.extend_from_slice(&(height * 2).to_le_bytes());
+      ico.extend_from_slice(&[1, 0, 32, 0, 0, 0, 0, 0]);
+      ico.resize(22 + 44, 0);
+      ico
+    };
+    assert!(super::ico::largest_png(&ico(1, 1)).is_some());
+    assert_eq!(super::ico::largest_png(&ico(0, 1)), None);
+    assert_eq!(super::ico::largest_png(&ico(1, 0)), None);
+    assert_eq!(super::ico::largest_png(&ico(u32::MAX, 0x7fff_ffff)), None);
+  }
+
+  #[test]
+  fn resolve_reference() {
+    use super::js_css::resolve_reference;
+
+    let ctx = super::Context::new(Config::default(), PathBuf::from("root"), "");
+    assert_eq!(
+      resolve_reference(&ctx, "https://example.com/css/app.css", "../logo.png"),
+      "https://example.com/logo.png"
+    );
+    assert_eq!(
+      resolve_reference(&ctx, "css/app.css", "logo.png"),
+      "root/css/logo.png"
+    );
+    // a malformed URL is left as is rather than aborting the run
+    assert_eq!(
+      resolve_reference(&ctx, "https://example.com/app.css", "http://[oops"),
+      "http://[oops"
+    );
+  }
+
+  #[test]
+  fn seed() {
+    let run = |seed| {
+      let config = Config {
+        seed,
+        ..Default::default()
+      };
+      let mut ctx = super::Context::new(config, PathBuf::new(), "");
+      ctx.rng.alphanumeric(16)
+    };
+    assert_eq!(run(Some(1)), run(Some(1)));
+    assert_ne!(run(Some(1)), run(Some(2)));
+    assert_ne!(run(None), run(None));
+  }
+
+  #[test]
+  fn estimate_output_size() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures");
+    for fixture in [
+      "picture-source",
+      "object-embed",
+      "audio",
+      "apple-touch-startup-image",
+    ] {
+      let path = root.join(format!("{}.src.html", fixture));
+      let report = super::analyze(&path, Config::default()).unwrap();
+      let estimate = super::estimate_output_size(&report) as f64;
+      let actual = super::inline_file(&path, Config::default()).unwrap().len() as f64;
+      assert!(
+        (estimate - actual).abs() / actual < 0.05,
+        "{}: estimated {} bytes, got {}",
+        fixture,
+        estimate,
+        actual
+      );
+    }
+  }
+
+  #[test]
+  fn tauri_integration() {
+    use super::tauri_integration::{inline_dist_dir, TauriOptions};
+
+    let dist_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures/dist");
+    let tauri_conf = serde_json::json!({
+      "build": { "distDir": "../dist", "inliner": { "exclude": ["*.hot-update.js"] } }
+    });
+    let options = TauriOptions::from_tauri_conf(&tauri_conf);
+    assert_eq!(options.exclude, vec!["*.hot-update.js".to_string()]);
+    assert!(!options.inline_remote);
+
+    let output = inline_dist_dir(&dist_dir, &options).unwrap();
+    let paths: Vec<_> = output.artifacts.iter().map(|a| a.path.clone()).collect();
+    assert_eq!(
+      paths,
+      vec![
+        PathBuf::from("index.html"),
+        PathBuf::from("nested/page.html")
+      ]
+    );
+    #[cfg(not(feature = "css-minify"))]
+    assert!(output.artifacts[0]
+      .html
+      .contains("<style>body { margin: 0; } </style>"));
+    #[cfg(feature = "css-minify")]
+    assert!(output.artifacts[0]
+      .html
+      .contains("<style>body{margin:0}</style>"));
+    assert!(output.artifacts[1].html.contains(r#"src="data:image/gif"#));
+    assert!(output.artifacts[1]
+      .html
+      .contains(r#"<script src="../main.hot-update.js"></script>"#));
+    assert_eq!(output.report.assets.len(), 4);
+  }
+
+  #[test]
+  fn query_policy() {
+    assert!(QueryPolicy::StripLocal.keeps_query("https://cdn.example.com/app.js?sig=abc"));
+    assert!(!QueryPolicy::StripLocal.keeps_query("app.js?v=1"));
+    assert!(!QueryPolicy::Strip.keeps_query("https://cdn.example.com/app.js?sig=abc"));
+    assert!(QueryPolicy::Preserve.keeps_query("app.js?v=1"));
+    let custom = QueryPolicy::Custom(std::sync::Arc::new(|path| path.contains("sig=")));
+    assert!(custom.keeps_query("https://cdn.example.com/app.js?sig=abc"));
+    assert!(!custom.keeps_query("https://cdn.example.com/app.js?v=1"));
+  }
+
+  /// The minifier features the fixture results depend on, e.g. `["css-minify", "js-minify"]`.
+  fn minifier_features() -> Vec<&'static str> {
+    vec![
+      #[cfg(feature = "css-minify")]
+      "css-minify",
+      #[cfg(feature = "js-minify")]
+      "js-minify",
+    ]
+  }
+
+  /// The expected output of a fixture: `<name>.<features>.result.html` when the enabled minifiers
+  /// change it, falling back to the result of a single minifier and then to `<name>.result.html`.
+  fn fixture_result(fixtures_path: &Path, name: &str) -> PathBuf {
+    let features = minifier_features();
+    let mut candidates = vec![features.join(".")];
+    if features.len() > 1 {
+      candidates.extend(features.iter().map(|f| f.to_string()));
+    }
+    candidates
+      .iter()
+      .filter(|f| !f.is_empty())
+      .map(|f| fixtures_path.join(format!("{}.{}.result.html", name, f)))
+      .find(|path| path.exists())
+      .unwrap_or_else(|| fixtures_path.join(format!("{}.result.html", name)))
+  }
+
   #[test]
   fn match_fixture() {
     env_logger::init();
@@ -238,7 +2585,8 @@ mod tests {
     spawn(move || {
       let server = Server::http("localhost:54321").unwrap();
       for request in server.incoming_requests() {
-        let requested = percent_encoding::percent_decode_str(request.url())
+        let requested = request.url().split('?').next().unwrap();
+        let requested = percent_encoding::percent_decode_str(requested)
           .decode_utf8_lossy()
           .to_string();
         let url: PathBuf = requested.chars().skip(1).collect::<String>().into();
@@ -249,9 +2597,8 @@ mod tests {
             .get(file_path.extension().unwrap().to_str().unwrap())
             .map(|c| c.to_string())
             .unwrap_or_else(|| "application/octet-stream".to_string());
-          response.add_header(
-            Header::from_bytes(&b"Content-Type"[..], &content_type.as_bytes()[..]).unwrap(),
-          );
+          response
+            .add_header(Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap());
           request.respond(response).unwrap();
         } else {
           request
@@ -268,26 +2615,42 @@ mod tests {
         continue;
       }
 
-      let output = super::inline_file(&path, Default::default()).unwrap();
+      let output =
+        super::inline_file(&path, fixture_config(&file_name.replace(".src.html", ""))).unwrap();
 
-      let expected = read_to_string(
-        path
-          .parent()
-          .unwrap()
-          .join(file_name.replace(".src.html", ".result.html")),
-      )
+      let expected = read_to_string(fixture_result(
+        path.parent().unwrap(),
+        &file_name.replace(".src.html", ""),
+      ))
       .unwrap();
 
       let not_equal = output
         .chars()
         .filter(|c| *c as u32 != 13)
-        .zip(expected.chars().filter(|c| *c as u32 != 13))
-        .any(|(a, b)| a != b);
+        .ne(expected.chars().filter(|c| *c as u32 != 13));
 
       if not_equal {
         _print_diff(output, expected);
         panic!("test case `{}` failed", file_name.replace(".src.html", ""));
       }
+
+      // inlining the output again is a no-op, besides the imports left over the max depth
+      if ["import-depth.src.html", "import-hoist.src.html"].contains(&file_name) {
+        continue;
+      }
+      let rerun = super::inline_html_string(
+        &output,
+        path.parent().unwrap(),
+        fixture_config(&file_name.replace(".src.html", "")),
+      )
+      .unwrap();
+      if rerun != output {
+        _print_diff(rerun, output);
+        panic!(
+          "test case `{}` changed when inlined again",
+          file_name.replace(".src.html", "")
+        );
+      }
     }
   }
 
@@ -304,7 +2667,7 @@ mod tests {
         }
         Chunk::Insert(x) => {
           match difference[i - 1] {
-            Chunk::Delete(ref y) => {
+            Chunk::Delete(y) => {
               stdout
                 .set_color(ColorSpec::new().set_fg(Some(Color::Green)))
                 .unwrap();
@@ -0,0 +1,103 @@
+use kuchiki::NodeRef;
+use serde_json::Value;
+use url::Url;
+
+use std::path::Path;
+
+use crate::Context;
+
+/// Resolves a URL of the manifest at `manifest_path` against the manifest's location.
+fn resolve_member(manifest_path: &str, src: &str) -> String {
+  if Url::parse(src).is_ok() || src.starts_with('/') {
+    src.to_string()
+  } else if let Ok(manifest_url) = Url::parse(manifest_path) {
+    manifest_url
+      .join(src)
+      .map(|url| url.to_string())
+      .unwrap_or_else(|_| src.to_string())
+  } else {
+    Path::new(manifest_path)
+      .parent()
+      .unwrap_or_else(|| Path::new(""))
+      .join(src)
+      .to_string_lossy()
+      .to_string()
+  }
+}
+
+/// Inlines the `src` of every image resource of a manifest member (`icons`, `screenshots`).
+fn inline_images(ctx: &mut Context, manifest_path: &str, images: &mut Value) -> crate::Result<()> {
+  for image in images.as_array_mut().into_iter().flatten() {
+    if let Some(src) = image.get("src").and_then(|src| src.as_str()) {
+      let path = resolve_member(manifest_path, src);
+      // an image left external is now relative to the document
+      let resolved = crate::get(ctx, &path)?.unwrap_or(path);
+      image["src"] = Value::String(resolved);
+    }
+  }
+  Ok(())
+}
+
+/// Inlines the web app manifests of `<link rel="manifest">` elements along with their images.
+///
+/// Relative URLs of the manifest other than its images (e.g. `start_url`) are left as is, and
+/// resolve against the document once it's inlined.
+pub fn inline_manifests(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  let links: Vec<NodeRef> = document
+    .select("link[rel][href]")
+    .unwrap()
+    .filter(|link| {
+      link
+        .attributes
+        .borrow()
+        .get("rel")
+        .unwrap()
+        .split_ascii_whitespace()
+        .any(|rel| rel.eq_ignore_ascii_case("manifest"))
+    })
+    .map(|link| link.as_node().clone())
+    .collect();
+
+  for node in links {
+    let element = node.as_element().unwrap();
+    let href = element.attributes.borrow().get("href").unwrap().to_string();
+    let path = ctx.resolve(&href);
+    let text = match crate::get(ctx, &path)? {
+      Some(text) => text,
+      None => continue,
+    };
+    let mut manifest: Value = match serde_json::from_str(&text) {
+      Ok(manifest) => manifest,
+      Err(e) => {
+        log::warn!("[INLINER] `{}` is not a valid manifest: {}", href, e);
+        continue;
+      }
+    };
+
+    log::debug!("[INLINER] inlining manifest {}", href);
+    for member in ["icons", "screenshots"] {
+      if let Some(images) = manifest.get_mut(member) {
+        inline_images(ctx, &path, images)?;
+      }
+    }
+    if let Some(shortcuts) = manifest.get_mut("shortcuts").and_then(|s| s.as_array_mut()) {
+      for shortcut in shortcuts {
+        if let Some(icons) = shortcut.get_mut("icons") {
+          inline_images(ctx, &path, icons)?;
+        }
+      }
+    }
+
+    let data_uri = format!(
+      "data:application/manifest+json;base64,{}",
+      crate::encode_base64(manifest.to_string().as_bytes())
+    );
+    let mut attributes = element.attributes.borrow_mut();
+    if ctx.config.annotate_sources {
+      attributes.insert(crate::ANNOTATION_ATTRIBUTE, href);
+    }
+    attributes.insert("href", data_uri);
+  }
+
+  Ok(())
+}
@@ -0,0 +1,282 @@
+use kuchiki::NodeRef;
+use once_cell::sync::Lazy;
+
+use crate::{html, js_css, Context, WarningKind};
+
+/// The specifiers of the static `import` and `export ... from` declarations of a module.
+static STATIC_IMPORT: Lazy<regex::Regex> = Lazy::new(|| {
+  regex::Regex::new(
+    r#"(\b(?:import|export)\b[\w\s{},*$]*?\bfrom\s*|\bimport\s*)(["'])([^"'\r\n]+)(["'])"#,
+  )
+  .unwrap()
+});
+/// The specifiers of the `import()` calls of a module, when they're string literals.
+static DYNAMIC_IMPORT: Lazy<regex::Regex> = Lazy::new(|| {
+  regex::Regex::new(r#"(\bimport\s*\(\s*)(["'])([^"'\r\n]+)(["'])(\s*[,)])"#).unwrap()
+});
+
+/// Escapes of the data URIs the modules and deferred scripts are embedded as: enough to hold them
+/// in a JS string and keep them out of the URL parser's way.
+pub const MODULE_DATA_URI_ESCAPES: &percent_encoding::AsciiSet = &percent_encoding::CONTROLS
+  .add(b'"')
+  .add(b'#')
+  .add(b'%')
+  .add(b'\'')
+  .add(b'\\')
+  .add(b'`');
+
+/// The `imports` of the document's `<script type="importmap">` elements, which resolve the bare
+/// specifiers of the bundled modules.
+#[derive(Default)]
+pub struct ImportMap {
+  /// The specifiers and their URLs, in the order of the document; the prefixes end with `/`.
+  imports: Vec<(String, String)>,
+  /// Whether every bare specifier of the bundled modules was resolved and bundled, see
+  /// `Config::remove_import_map`.
+  complete: bool,
+}
+
+impl ImportMap {
+  fn parse(document: &NodeRef) -> Self {
+    let mut import_map = ImportMap {
+      imports: Vec::new(),
+      complete: true,
+    };
+    for script in document.select("script[type=importmap]").unwrap() {
+      let map: serde_json::Value = match serde_json::from_str(&script.text_contents()) {
+        Ok(map) => map,
+        Err(e) => {
+          log::debug!("[INLINER] invalid import map: {}", e);
+          import_map.complete = false;
+          continue;
+        }
+      };
+      // the scoped specifiers aren't resolved, so their modules may still need the map
+      if map["scopes"]
+        .as_object()
+        .is_some_and(|scopes| !scopes.is_empty())
+      {
+        import_map.complete = false;
+      }
+      if let Some(imports) = map["imports"].as_object() {
+        import_map.imports.extend(
+          imports
+            .iter()
+            .filter_map(|(specifier, url)| Some((specifier.clone(), url.as_str()?.to_string()))),
+        );
+      }
+    }
+    import_map
+  }
+
+  /// The URL the bare `specifier` is mapped to: an exact match, or the longest prefix.
+  fn resolve(&self, specifier: &str) -> Option<String> {
+    if let Some((_, url)) = self.imports.iter().find(|(key, _)| key == specifier) {
+      return Some(url.clone());
+    }
+    self
+      .imports
+      .iter()
+      .filter(|(key, _)| key.ends_with('/') && specifier.starts_with(key.as_str()))
+      .max_by_key(|(key, _)| key.len())
+      .map(|(key, url)| format!("{}{}", url, &specifier[key.len()..]))
+  }
+}
+
+/// Whether `specifier` references a file rather than a package resolved by an import map.
+fn is_file_specifier(specifier: &str) -> bool {
+  specifier.starts_with("./")
+    || specifier.starts_with("../")
+    || specifier.starts_with("http://")
+    || specifier.starts_with("https://")
+}
+
+/// Rewrites the file imports of the module at `module_path` to data URIs of the modules they
+/// import, themselves bundled. Imports that can't be loaded are left as is.
+fn bundle(ctx: &mut Context, module: &str, module_path: &str) -> crate::Result<String> {
+  let bundled = rewrite_imports(ctx, module, module_path, &STATIC_IMPORT)?;
+  if ctx.config.bundle_dynamic_imports {
+    rewrite_imports(ctx, &bundled, module_path, &DYNAMIC_IMPORT)
+  } else {
+    if DYNAMIC_IMPORT
+      .captures_iter(&bundled)
+      .any(|captures| !is_file_specifier(&captures[3]))
+    {
+      ctx.import_map.complete = false;
+    }
+    Ok(bundled)
+  }
+}
+
+/// Rewrites the specifiers `imports` finds: its captures are the text before the specifier, its
+/// quotes around it and optionally the text after it.
+fn rewrite_imports(
+  ctx: &mut Context,
+  module: &str,
+  module_path: &str,
+  imports: &regex::Regex,
+) -> crate::Result<String> {
+  let mut bundled = String::with_capacity(module.len());
+  let mut last = 0;
+  for captures in imports.captures_iter(module) {
+    let whole = captures.get(0).unwrap();
+    bundled.push_str(&module[last..whole.start()]);
+    last = whole.end();
+    let specifier = &captures[3];
+    if captures[2] != captures[4] {
+      bundled.push_str(whole.as_str());
+      continue;
+    }
+    let path = if is_file_specifier(specifier) {
+      js_css::resolve_reference(ctx, module_path, specifier)
+    } else if let Some(url) = ctx.import_map.resolve(specifier) {
+      js_css::resolve_reference(ctx, &ctx.document_base(), &url)
+    } else {
+      ctx.import_map.complete = false;
+      bundled.push_str(whole.as_str());
+      continue;
+    };
+    let data_uri = inline_import(ctx, module_path, specifier, &path)?;
+    if data_uri.is_none() && !is_file_specifier(specifier) {
+      ctx.import_map.complete = false;
+    }
+    match data_uri {
+      Some(data_uri) => bundled.push_str(&format!(
+        "{}\"{}\"{}",
+        &captures[1],
+        data_uri,
+        captures.get(5).map_or("", |suffix| suffix.as_str())
+      )),
+      None => bundled.push_str(whole.as_str()),
+    }
+  }
+  bundled.push_str(&module[last..]);
+  Ok(bundled)
+}
+
+/// The data URI of the module at `path`, which `specifier` of the module at `module_path`
+/// references.
+fn inline_import(
+  ctx: &mut Context,
+  module_path: &str,
+  specifier: &str,
+  path: &str,
+) -> crate::Result<Option<String>> {
+  let key = ctx.asset_key(path);
+  if ctx.import_chain.contains(&key) {
+    let message = format!(
+      "`{}` imports `{}`, which imports it in turn; the import was left as is",
+      module_path, specifier
+    );
+    ctx.warn(WarningKind::CircularImport, message);
+    return Ok(None);
+  }
+  let module = match crate::get(ctx, path)? {
+    Some(module) => {
+      let module = js_css::apply_source_map_policy(ctx, module, path)?;
+      js_css::compress_js(ctx.config.transform_level, module)
+    }
+    None => return Ok(None),
+  };
+  ctx.import_chain.push(key);
+  let bundled = bundle(ctx, &module, path);
+  ctx.import_chain.pop();
+  let bundled = bundled?;
+  // the URL parser would trim the trailing whitespace
+  Ok(Some(format!(
+    "data:text/javascript,{}",
+    percent_encoding::utf8_percent_encode(bundled.trim_end(), MODULE_DATA_URI_ESCAPES)
+  )))
+}
+
+/// Inlines the `<script type="module">` elements along with the modules they import, which are
+/// embedded as data URIs, see `Config::bundle_modules`.
+pub fn bundle_modules(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  if !ctx.config.bundle_modules {
+    return Ok(());
+  }
+  ctx.import_map = ImportMap::parse(document);
+  let scripts: Vec<NodeRef> = document
+    .select("script[type=module]")
+    .unwrap()
+    .map(|script| script.as_node().clone())
+    .collect();
+
+  for node in scripts {
+    let element = node.as_element().unwrap();
+    let source = element.attributes.borrow().get("src").map(str::to_string);
+    let (module, module_path) = match &source {
+      Some(source) => {
+        if !html::allows_inlining(ctx, &element.attributes.borrow(), source) {
+          ctx.import_map.complete = false;
+          continue;
+        }
+        let path = ctx.resolve(source);
+        let integrity = element
+          .attributes
+          .borrow()
+          .get("integrity")
+          .map(str::to_string);
+        match crate::get_verified(ctx, &path, integrity.as_deref())? {
+          Some(module) => {
+            let module = js_css::apply_source_map_policy(ctx, module, &path)?;
+            (
+              js_css::compress_js(ctx.config.transform_level, module),
+              path,
+            )
+          }
+          None => {
+            ctx.import_map.complete = false;
+            continue;
+          }
+        }
+      }
+      None => (node.text_contents(), ctx.document_base()),
+    };
+    let key = ctx.asset_key(&module_path);
+    ctx.import_chain.push(key);
+    let bundled = bundle(ctx, &module, &module_path);
+    ctx.import_chain.pop();
+    let bundled = bundled?;
+    if source.is_none() && bundled == module {
+      continue;
+    }
+
+    log::debug!(
+      "[INLINER] bundled the module {}",
+      source.as_deref().unwrap_or("<inline>")
+    );
+    {
+      let mut attributes = element.attributes.borrow_mut();
+      for name in js_css::SCRIPT_SKIPPED_ATTRIBUTES {
+        attributes.remove(*name);
+      }
+    }
+    for child in node.children() {
+      child.detach();
+    }
+    node.append(NodeRef::new_text(js_css::escape_raw_text(
+      &bundled, "script",
+    )));
+    if let Some(source) = &source {
+      ctx.annotate(&node, source);
+    }
+    // an inline module without a nonce stays blocked by a policy relying on nonces
+    if !ctx.csp_nonces || element.attributes.borrow().contains("nonce") {
+      ctx.generate(&node);
+    }
+  }
+
+  if ctx.config.remove_import_map && ctx.import_map.complete {
+    for import_map in document
+      .select("script[type=importmap]")
+      .unwrap()
+      .collect::<Vec<_>>()
+    {
+      log::debug!("[INLINER] removing the import map");
+      import_map.as_node().detach();
+    }
+  }
+
+  Ok(())
+}
@@ -0,0 +1,86 @@
+/// The kind of a [`Warning`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum WarningKind {
+  /// An asset was left external because of `Config::max_output_size`.
+  OutputSizeExceeded,
+  /// A CSS `@import` was left as is because of `Config::max_import_depth`.
+  ImportDepthExceeded,
+  /// A `nomodule` fallback script was removed because of `Config::nomodule`.
+  NomoduleRemoved,
+  /// An `http` reference remains in a document served over `https`.
+  MixedContent,
+  /// An empty or whitespace-only `src`/`href` was skipped.
+  EmptyReference,
+  /// A CSS `@import` of a stylesheet that is already being imported was dropped, or an ES module
+  /// import of a module that is already being bundled was left as is.
+  CircularImport,
+  /// A script registers a service worker, see `Config::service_workers`.
+  ServiceWorker,
+  /// The content of a script or stylesheet doesn't match its `integrity` metadata, see
+  /// `Config::integrity`.
+  IntegrityMismatch,
+  /// A script or stylesheet without a nonce was left external since the document's
+  /// `Content-Security-Policy` relies on nonces, see `Config::csp_nonce`.
+  MissingNonce,
+}
+
+/// A notable decision or problem encountered while inlining.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+  pub kind: WarningKind,
+  pub message: String,
+}
+
+/// An asset referenced by the document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Asset {
+  /// The reference as resolved by the inliner.
+  pub path: String,
+  /// The content type guessed from the asset's extension.
+  pub content_type: Option<String>,
+  /// Size of the asset once embedded (e.g. as a data URI); `None` if it could not be loaded.
+  pub size: Option<usize>,
+  /// Size of the asset as loaded, before it is encoded and before its own assets are inlined.
+  pub raw_size: Option<usize>,
+  /// Whether the asset was inlined or remains an external reference.
+  pub inlined: bool,
+}
+
+/// Progress of an inlining run, passed to `Config::on_event`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InlineEvent {
+  /// An asset reference is about to be resolved.
+  Loading { path: String },
+  /// An asset reference was resolved, as recorded in `Report::assets`.
+  Resolved(Asset),
+}
+
+/// What happened during an inlining run.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+  /// Warnings in the order they were encountered.
+  pub warnings: Vec<Warning>,
+  /// Every asset reference, in the order they were resolved.
+  pub assets: Vec<Asset>,
+  /// Size of the input document.
+  pub document_size: usize,
+}
+
+impl Report {
+  /// Total size of the inlined assets.
+  pub fn inlined_size(&self) -> usize {
+    self
+      .assets
+      .iter()
+      .filter(|asset| asset.inlined)
+      .filter_map(|asset| asset.size)
+      .sum()
+  }
+
+  /// Whether a warning of the given kind was emitted.
+  pub fn has_warning(&self, kind: WarningKind) -> bool {
+    self.warnings.iter().any(|warning| warning.kind == kind)
+  }
+}
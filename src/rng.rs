@@ -0,0 +1,31 @@
+use std::{
+  collections::hash_map::RandomState,
+  hash::{BuildHasher, Hasher},
+};
+
+/// A small splitmix64 generator, so a run's random choices (nonces, ids, tie-breaking) can be
+/// reproduced with `Config::seed`.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+  /// Seeds the generator with `seed`, or with the process' random hasher keys.
+  pub(crate) fn new(seed: Option<u64>) -> Self {
+    Self(seed.unwrap_or_else(|| RandomState::new().build_hasher().finish()))
+  }
+
+  pub(crate) fn next_u64(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+  }
+
+  /// `len` random alphanumeric characters.
+  pub(crate) fn alphanumeric(&mut self, len: usize) -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+    (0..len)
+      .map(|_| CHARS[(self.next_u64() % CHARS.len() as u64) as usize] as char)
+      .collect()
+  }
+}
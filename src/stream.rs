@@ -0,0 +1,51 @@
+use futures_channel::{mpsc, oneshot};
+use futures_core::Stream;
+
+use std::{
+  future::Future,
+  path::PathBuf,
+  pin::Pin,
+  task::{Context, Poll},
+};
+
+use crate::{Config, EventHook, InlineEvent, Result};
+
+/// The output of a run started with `inline_file_stream()`, resolved once the run is over.
+pub struct InlineOutput(oneshot::Receiver<Result<String>>);
+
+impl Future for InlineOutput {
+  type Output = Result<String>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    Pin::new(&mut self.0)
+      .poll(cx)
+      .map(|output| output.expect("the inlining thread panicked"))
+  }
+}
+
+/// Inlines the html file at file path on a background thread, returning the stream of its
+/// `InlineEvent`s, which ends with the run, and its output.
+/// `Config::on_event` is still called with every event.
+pub fn inline_file_stream<P: Into<PathBuf>>(
+  file_path: P,
+  mut config: Config,
+) -> (impl Stream<Item = InlineEvent> + Unpin + Send, InlineOutput) {
+  let (events_tx, events_rx) = mpsc::unbounded();
+  let (output_tx, output_rx) = oneshot::channel();
+
+  let hook = config.on_event.take();
+  config.on_event.replace(EventHook::new(move |event| {
+    if let Some(hook) = &hook {
+      (hook.0)(event);
+    }
+    // the receiver may have been dropped, the run goes on
+    let _ = events_tx.unbounded_send(event.clone());
+  }));
+
+  let file_path = file_path.into();
+  std::thread::spawn(move || {
+    let _ = output_tx.send(crate::inline_file(file_path, config));
+  });
+
+  (events_rx, InlineOutput(output_rx))
+}
@@ -0,0 +1,328 @@
+use html5ever::QualName;
+use kuchiki::{traits::TendrilSink, ElementData, NodeRef};
+
+use once_cell::sync::Lazy;
+
+use std::collections::HashMap;
+
+use crate::{js_css, Asset, Context, SvgMode, SvgSprites};
+
+/// Attributes of an `<img>` carried over to the `<svg>` replacing it.
+static IMG_CARRIED_ATTRIBUTES: &[&str] = &["width", "height", "class", "id", "style"];
+/// The `<style>` elements of an SVG file, with their optional CDATA section.
+static STYLE: Lazy<regex::Regex> = Lazy::new(|| {
+  regex::Regex::new(r"(?is)(<style\b[^>]*>)(\s*<!\[CDATA\[)?(.*?)(\]\]>\s*)?(</style\s*>)").unwrap()
+});
+/// The `href` (or legacy `xlink:href`) of the `<image>` elements of an SVG file.
+static IMAGE_HREF: Lazy<regex::Regex> = Lazy::new(|| {
+  regex::Regex::new(r#"(?is)(<image\b[^>]*?\s(?:xlink:)?href\s*=\s*)(?:"([^"]*)"|'([^']*)')"#)
+    .unwrap()
+});
+
+/// The `href` (or legacy `xlink:href`) of an element.
+fn href(element: &ElementData) -> Option<String> {
+  element
+    .attributes
+    .borrow()
+    .map
+    .iter()
+    .find(|(name, _)| &*name.local == "href")
+    .map(|(_, attribute)| attribute.value.clone())
+}
+
+/// Parses `sprite` and returns a detached copy of its `<symbol id="{id}">`.
+fn find_symbol(sprite: &str, id: &str) -> Option<NodeRef> {
+  let document = kuchiki::parse_html().one(sprite);
+  let symbol = document
+    .select("symbol")
+    .unwrap()
+    .find(|symbol| symbol.attributes.borrow().get("id") == Some(id))?;
+  let symbol = symbol.as_node().clone();
+  symbol.detach();
+  Some(symbol)
+}
+
+/// Creates the hidden `<svg>` holding the copied symbols at the start of the body.
+fn create_block(document: &NodeRef) -> NodeRef {
+  let block = NodeRef::new_element(
+    QualName::new(None, ns!(svg), local_name!("svg")),
+    vec![
+      (
+        kuchiki::ExpandedName::new(ns!(), "style"),
+        kuchiki::Attribute {
+          prefix: None,
+          value: "display: none".into(),
+        },
+      ),
+      (
+        kuchiki::ExpandedName::new(ns!(), "aria-hidden"),
+        kuchiki::Attribute {
+          prefix: None,
+          value: "true".into(),
+        },
+      ),
+    ],
+  );
+  let parent = document
+    .select_first("body")
+    .map(|body| body.as_node().clone())
+    .unwrap_or_else(|_| document.clone());
+  parent.prepend(block.clone());
+  block
+}
+
+/// Resolves the `<use>` elements referencing a symbol of an external sprite, see `SvgSprites`.
+pub fn inline_sprites(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  let policy = ctx.config.svg_sprites;
+  if policy == SvgSprites::Keep {
+    return Ok(());
+  }
+  let uses: Vec<NodeRef> = document
+    .select("use")
+    .unwrap()
+    .filter(|element| {
+      href(element).is_some_and(|href| !href.starts_with('#') && href.contains('#'))
+    })
+    .map(|element| element.as_node().clone())
+    .collect();
+
+  // the sprites' text, by path
+  let mut sprites: HashMap<String, Option<String>> = HashMap::new();
+  // the ids of the symbols copied to the block, with the sprite they come from
+  let mut copied: HashMap<String, String> = HashMap::new();
+  let mut block = None;
+
+  for node in uses {
+    let element = node.as_element().unwrap();
+    let reference = href(element).unwrap();
+    let (path, id) = reference.split_once('#').unwrap();
+    let path = ctx.resolve(path);
+    if !sprites.contains_key(&path) {
+      let sprite = crate::load_text(ctx, &path);
+      ctx.record(Asset {
+        path: path.clone(),
+        content_type: Some("image/svg+xml".into()),
+        size: sprite.as_ref().map(|sprite| sprite.len()),
+        raw_size: sprite.as_ref().map(|sprite| sprite.len()),
+        inlined: sprite.is_some(),
+      });
+      sprites.insert(path.clone(), sprite);
+    }
+    let symbol = match sprites[&path]
+      .as_deref()
+      .and_then(|sprite| find_symbol(sprite, id))
+    {
+      Some(symbol) => symbol,
+      None => {
+        log::debug!("[INLINER] symbol `{}` not found", reference);
+        continue;
+      }
+    };
+
+    match policy {
+      SvgSprites::Block => {
+        match copied.get(id) {
+          Some(sprite) if *sprite != path => {
+            log::warn!(
+              "[INLINER] `{}` has the same id as a symbol of `{}` and will not be inlined",
+              reference,
+              sprite
+            );
+            continue;
+          }
+          Some(_) => {}
+          None => {
+            if !ctx.reserve_output(&reference, symbol.to_string().len())? {
+              continue;
+            }
+            block
+              .get_or_insert_with(|| create_block(document))
+              .append(symbol);
+            copied.insert(id.to_string(), path.clone());
+          }
+        }
+        let mut attributes = element.attributes.borrow_mut();
+        for (name, attribute) in attributes.map.iter_mut() {
+          if &*name.local == "href" {
+            attribute.value = format!("#{}", id);
+          }
+        }
+      }
+      SvgSprites::Replace => {
+        if !ctx.reserve_output(&reference, symbol.to_string().len())? {
+          continue;
+        }
+        // a symbol is rendered as a nested `<svg>` sized by the `<use>`
+        let symbol_element = symbol.as_element().unwrap();
+        let mut attributes = symbol_element.attributes.borrow().map.clone();
+        attributes.remove(&kuchiki::ExpandedName::new(ns!(), "id"));
+        for (name, attribute) in &element.attributes.borrow().map {
+          if ["x", "y", "width", "height"].contains(&&*name.local) {
+            attributes.insert(name.clone(), attribute.clone());
+          }
+        }
+        let replacement = NodeRef::new_element(
+          QualName::new(None, ns!(svg), local_name!("svg")),
+          attributes,
+        );
+        let children: Vec<NodeRef> = symbol.children().collect();
+        for child in children {
+          replacement.append(child);
+        }
+        node.insert_after(replacement);
+        node.detach();
+      }
+      SvgSprites::Keep => unreachable!(),
+    }
+  }
+
+  Ok(())
+}
+
+/// Replaces the `<img>` elements referencing an SVG file with its `<svg>`, see `SvgMode`.
+pub fn inline_images(ctx: &mut Context, document: &NodeRef) -> crate::Result<()> {
+  if ctx.config.svg_mode != SvgMode::InlineElement {
+    return Ok(());
+  }
+  let images: Vec<NodeRef> = document
+    .select("img[src]:not([srcset])")
+    .unwrap()
+    // the `<source>` elements of a `<picture>` may select another image
+    .filter(|img| {
+      img
+        .as_node()
+        .parent()
+        .and_then(|parent| parent.into_element_ref())
+        .is_none_or(|parent| &*parent.name.local != "picture")
+    })
+    .map(|img| img.as_node().clone())
+    .collect();
+
+  for node in images {
+    let element = node.as_element().unwrap();
+    let source = element.attributes.borrow().get("src").unwrap().to_string();
+    let path = ctx.resolve(&source);
+    if crate::asset_content_type(&path) != Some("image/svg+xml") || path.contains('#') {
+      continue;
+    }
+    // left to `binary::inline_base64` when it can't be loaded
+    let text = match crate::load_text(ctx, &path) {
+      Some(text) => text,
+      None => continue,
+    };
+    let svg = match kuchiki::parse_html()
+      .one(text.as_str())
+      .select_first("svg")
+      .ok()
+    {
+      Some(svg) => svg.as_node().clone(),
+      None => {
+        log::debug!("[INLINER] `{}` has no <svg> element", source);
+        continue;
+      }
+    };
+    svg.detach();
+    if !ctx.reserve_output(&path, svg.to_string().len())? {
+      continue;
+    }
+
+    {
+      let img_attributes = element.attributes.borrow();
+      let mut attributes = svg.as_element().unwrap().attributes.borrow_mut();
+      for name in IMG_CARRIED_ATTRIBUTES {
+        if let Some(value) = img_attributes.get(*name) {
+          attributes.insert(*name, value.to_string());
+        }
+      }
+      match img_attributes.get("alt") {
+        Some("") => {
+          attributes.insert("aria-hidden", "true".into());
+        }
+        Some(alt) => {
+          attributes.insert("role", "img".into());
+          attributes.insert("aria-label", alt.to_string());
+        }
+        None => {}
+      }
+    }
+    ctx.record(Asset {
+      path: path.clone(),
+      content_type: Some("image/svg+xml".into()),
+      size: Some(svg.to_string().len()),
+      raw_size: Some(text.len()),
+      inlined: true,
+    });
+    ctx.annotate(&svg, &source);
+    node.insert_after(svg);
+    node.detach();
+  }
+
+  Ok(())
+}
+
+/// Inlines the assets referenced by the `<style>` and `<image>` elements of the SVG file at `path`,
+/// which can't be loaded anymore once it's a data URI. Left as is if it isn't UTF-8 or
+/// references itself.
+pub fn inline_references(ctx: &mut Context, path: &str, raw: Vec<u8>) -> crate::Result<Vec<u8>> {
+  let key = ctx.asset_key(path);
+  if ctx.import_chain.contains(&key) {
+    return Ok(raw);
+  }
+  let svg = match std::str::from_utf8(&raw) {
+    Ok(svg) if STYLE.is_match(svg) || IMAGE_HREF.is_match(svg) => svg.to_string(),
+    _ => return Ok(raw),
+  };
+  ctx.import_chain.push(key);
+  let res = inline_style_references(ctx, path, &svg)
+    .and_then(|svg| inline_image_references(ctx, path, &svg));
+  ctx.import_chain.pop();
+  res.map(String::into_bytes)
+}
+
+fn inline_style_references(ctx: &mut Context, path: &str, svg: &str) -> crate::Result<String> {
+  let mut out = String::with_capacity(svg.len());
+  let mut last = 0;
+  for captures in STYLE.captures_iter(svg) {
+    let css = js_css::rewrite_css(ctx, &captures[3], path)?;
+    let whole = captures.get(0).unwrap();
+    out.push_str(&svg[last..whole.start()]);
+    out.push_str(&captures[1]);
+    // the data URIs may hold characters to escape in XML
+    if captures.get(2).is_some() || css.contains(['<', '&']) {
+      out.push_str(&format!("<![CDATA[{}]]>", css));
+    } else {
+      out.push_str(&css);
+    }
+    out.push_str(&captures[5]);
+    last = whole.end();
+  }
+  out.push_str(&svg[last..]);
+  Ok(out)
+}
+
+fn inline_image_references(ctx: &mut Context, path: &str, svg: &str) -> crate::Result<String> {
+  let mut out = String::with_capacity(svg.len());
+  let mut last = 0;
+  for captures in IMAGE_HREF.captures_iter(svg) {
+    let whole = captures.get(0).unwrap();
+    let href = captures
+      .get(2)
+      .or_else(|| captures.get(3))
+      .unwrap()
+      .as_str();
+    let inlined = if href.starts_with("data:") || href.starts_with('#') {
+      None
+    } else {
+      let href_path = js_css::resolve_reference(ctx, path, href);
+      crate::get(ctx, &href_path)?
+    };
+    out.push_str(&svg[last..whole.start()]);
+    out.push_str(&captures[1]);
+    out.push('"');
+    out.push_str(inlined.as_deref().unwrap_or(href));
+    out.push('"');
+    last = whole.end();
+  }
+  out.push_str(&svg[last..]);
+  Ok(out)
+}
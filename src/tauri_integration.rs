@@ -0,0 +1,140 @@
+//! Entry point for the Tauri CLI: inlines every page of an app's dist dir.
+
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+use serde_json::Value;
+
+use crate::{AssetCache, Config, ExcludePattern, Report, Target};
+
+/// The `build.inliner` object of `tauri.conf.json`:
+///
+/// ```json
+/// { "build": { "inliner": { "inlineRemote": false, "maxInlineSize": 10000, "exclude": ["*.hot-update.js"] } } }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TauriOptions {
+  /// Whether remote assets are inlined, `inlineRemote`. Defaults to `false` since the bundle
+  /// shouldn't depend on the network at build time.
+  pub inline_remote: bool,
+  /// `maxInlineSize`, see `Config::max_inline_size`.
+  pub max_inline_size: usize,
+  /// `exclude`, globs matching the references that are never inlined.
+  pub exclude: Vec<String>,
+}
+
+impl Default for TauriOptions {
+  fn default() -> Self {
+    Self {
+      inline_remote: false,
+      max_inline_size: Config::default().max_inline_size,
+      exclude: Vec::new(),
+    }
+  }
+}
+
+impl TauriOptions {
+  /// Reads the options from the parsed `tauri.conf.json`, using the defaults for missing keys.
+  pub fn from_tauri_conf(tauri_conf: &Value) -> Self {
+    let defaults = Self::default();
+    let inliner = &tauri_conf["build"]["inliner"];
+    Self {
+      inline_remote: inliner["inlineRemote"]
+        .as_bool()
+        .unwrap_or(defaults.inline_remote),
+      max_inline_size: inliner["maxInlineSize"]
+        .as_u64()
+        .map(|size| size as usize)
+        .unwrap_or(defaults.max_inline_size),
+      exclude: inliner["exclude"]
+        .as_array()
+        .map(|patterns| {
+          patterns
+            .iter()
+            .filter_map(|pattern| pattern.as_str().map(|pattern| pattern.to_string()))
+            .collect()
+        })
+        .unwrap_or(defaults.exclude),
+    }
+  }
+
+  /// The `Config` used for the pages; Tauri's webviews are all evergreen.
+  pub fn config(&self) -> Config {
+    Config {
+      inline_remote: self.inline_remote,
+      max_inline_size: self.max_inline_size,
+      exclude: self
+        .exclude
+        .iter()
+        .map(|glob| ExcludePattern::Glob(glob.clone()))
+        .collect(),
+      target: Target::Modern,
+      ..Default::default()
+    }
+  }
+}
+
+/// An inlined page of the dist dir.
+#[derive(Debug, Clone)]
+pub struct Artifact {
+  /// Path of the page, relative to the dist dir.
+  pub path: PathBuf,
+  pub html: String,
+}
+
+/// What `inline_dist_dir()` produced.
+#[derive(Debug, Clone)]
+pub struct Output {
+  /// The inlined pages, sorted by path.
+  pub artifacts: Vec<Artifact>,
+  /// The reports of every page, merged.
+  pub report: Report,
+}
+
+/// Inlines every `.html` file of `dist_dir` (recursively), sharing the assets between pages.
+pub fn inline_dist_dir<P: AsRef<Path>>(
+  dist_dir: P,
+  options: &TauriOptions,
+) -> crate::Result<Output> {
+  let dist_dir = dist_dir.as_ref();
+  let mut pages = Vec::new();
+  find_pages(dist_dir, &mut pages)?;
+  pages.sort();
+
+  let config = Config {
+    cache: Some(AssetCache::new()),
+    ..options.config()
+  };
+  let mut output = Output {
+    artifacts: Vec::new(),
+    report: Report::default(),
+  };
+  for page in pages {
+    let (html, mut report) = crate::inline_file_with_report(&page, config.clone())?;
+    output.report.warnings.append(&mut report.warnings);
+    output.report.assets.append(&mut report.assets);
+    output.report.document_size += report.document_size;
+    output.artifacts.push(Artifact {
+      path: page.strip_prefix(dist_dir).unwrap().to_path_buf(),
+      html,
+    });
+  }
+  Ok(output)
+}
+
+fn find_pages(dir: &Path, pages: &mut Vec<PathBuf>) -> crate::Result<()> {
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    if path.is_dir() {
+      find_pages(&path, pages)?;
+    } else if path
+      .extension()
+      .is_some_and(|extension| extension == "html")
+    {
+      pages.push(path);
+    }
+  }
+  Ok(())
+}